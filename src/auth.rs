@@ -1,3 +1,6 @@
+use crate::difficulty::Difficulty;
+use crate::models::{GameSettings, GameState};
+use crate::texts::default_language;
 use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
 use serde::{Deserialize, Serialize};
 use std::env;
@@ -8,29 +11,91 @@ pub struct Claims {
     pub sub: String,
     pub user_id: String,
     pub username: String,
+    /// Skill rating used for matchmaking, if Laravel includes one
+    #[serde(default)]
+    pub rating: Option<u32>,
     pub exp: usize,
 }
 
-pub fn verify_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+/// Join ticket issued by Laravel, binding a user to one game's parameters.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GameTicket {
+    pub sub: String,
+    pub game_id: String,
+    pub game_code: String,
+    pub difficulty: Difficulty,
+    /// Language the race text is picked in
+    #[serde(default = "default_language")]
+    pub language: String,
+    /// User id of the game's host
+    pub host_id: String,
+    #[serde(default)]
+    pub settings: GameSettings,
+    pub exp: usize,
+}
+
+fn secret_bytes() -> Vec<u8> {
     let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
     
     // Handle Laravel's base64: prefix
-    let secret_bytes = if secret.starts_with("base64:") {
-        match general_purpose::STANDARD.decode(&secret[7..]) {
+    if let Some(encoded) = secret.strip_prefix("base64:") {
+        match general_purpose::STANDARD.decode(encoded) {
             Ok(bytes) => bytes,
             Err(_) => secret.as_bytes().to_vec(),
         }
     } else {
         secret.as_bytes().to_vec()
-    };
-    
+    }
+}
+
+pub fn verify_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
     let validation = Validation::new(Algorithm::HS256);
     
     let token_data = decode::<Claims>(
         token,
-        &DecodingKey::from_secret(&secret_bytes),
+        &DecodingKey::from_secret(&secret_bytes()),
         &validation,
     )?;
     
     Ok(token_data.claims)
 }
+
+pub fn verify_ticket(ticket: &str) -> Result<GameTicket, jsonwebtoken::errors::Error> {
+    let validation = Validation::new(Algorithm::HS256);
+
+    let token_data = decode::<GameTicket>(
+        ticket,
+        &DecodingKey::from_secret(&secret_bytes()),
+        &validation,
+    )?;
+
+    Ok(token_data.claims)
+}
+
+impl GameTicket {
+    /// Check the ticket was issued to `user_id` for exactly this game.
+    pub fn check(&self, user_id: &str, game: &GameState) -> Result<(), String> {
+        if self.sub != user_id {
+            return Err("Ticket was issued to another user".to_string());
+        }
+        if self.game_id != game.id {
+            return Err("Ticket is for another game".to_string());
+        }
+        if self.game_code != game.code {
+            return Err("Game code does not match ticket".to_string());
+        }
+        if self.difficulty != game.difficulty {
+            return Err("Difficulty does not match ticket".to_string());
+        }
+        if self.language != game.language {
+            return Err("Language does not match ticket".to_string());
+        }
+        if self.host_id != game.ticket_host_id {
+            return Err("Host does not match ticket".to_string());
+        }
+        if self.settings != game.settings {
+            return Err("Settings do not match ticket".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use crate::models::Player;
+use crate::typing::BACKSPACE;
+use rand::Rng;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How often a bot sends keystrokes while racing.
+pub const TICK: Duration = Duration::from_millis(250);
+
+/// Slowest and fastest target speed a bot can be given.
+pub const MIN_WPM: u32 = 10;
+pub const MAX_WPM: u32 = 200;
+
+/// How far a bot's speed drifts from its target on any one tick.
+const SPEED_VARIANCE: f64 = 0.25;
+
+/// Chance of each character being mistyped, and fixed again straight away.
+const ERROR_RATE: f64 = 0.03;
+
+/// A computer-controlled racer typing at around `wpm`.
+pub struct Bot {
+    pub wpm: u32,
+    /// Characters owed from earlier ticks, so slow bots still make progress.
+    /// Negative while a typo is being paid for.
+    carry: f64,
+}
+
+impl Bot {
+    /// A bot typing at `wpm`, kept under the server's `max_wpm` so its
+    /// keystrokes are never refused as too fast.
+    pub fn new(wpm: u32, max_wpm: u32) -> Self {
+        Self {
+            wpm: wpm.clamp(MIN_WPM, MAX_WPM.min(max_wpm).max(MIN_WPM)),
+            carry: 0.0,
+        }
+    }
+
+    /// The seat a bot takes in a game. Bots get a user id of their own so
+    /// they can never be mistaken for, or rated as, a real user.
+    pub fn player(&self, number: usize) -> Player {
+        let mut player = Player::new(
+            Uuid::new_v4().to_string(),
+            format!("bot:{}", Uuid::new_v4()),
+            format!("Bot {} ({} WPM)", number, self.wpm),
+        );
+        player.bot = true;
+        player
+    }
+
+    /// Keystrokes for one tick, continuing `text` from `position`.
+    pub fn keys(&mut self, text: &[char], position: usize) -> String {
+        let mut rng = rand::thread_rng();
+        // A word is five characters
+        let per_tick = self.wpm as f64 * 5.0 / 60.0 * TICK.as_secs_f64();
+        self.carry += per_tick * rng.gen_range(1.0 - SPEED_VARIANCE..1.0 + SPEED_VARIANCE);
+        let count = self.carry.floor() as usize;
+        self.carry -= count as f64;
+
+        let mut keys = String::new();
+        for &expected in text.iter().skip(position).take(count) {
+            if rng.gen_bool(ERROR_RATE) {
+                let typo = if expected == 'x' { 'z' } else { 'x' };
+                keys.push(typo);
+                keys.push(BACKSPACE);
+                // Both count towards the speed limit, so later ticks pay for them
+                self.carry -= 2.0;
+            }
+            keys.push(expected);
+        }
+        keys
+    }
+}
@@ -0,0 +1,99 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Tunables read from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a disconnected player keeps their seat before being removed
+    pub reconnect_grace: Duration,
+    /// Delay between the host starting the game and the race beginning
+    pub countdown: Duration,
+    /// Longest a race may run before unfinished players are marked DNF
+    pub race_timeout: Duration,
+    /// Longest a game may sit in the lobby before it is closed
+    pub lobby_timeout: Duration,
+    /// Pause between the rounds of a match
+    pub round_intermission: Duration,
+    /// How long a finished game stays open for a rematch vote
+    pub rematch_timeout: Duration,
+    /// Upper bound on how far finish times are moved back for latency
+    pub max_latency_compensation: Duration,
+    /// Fastest typing speed accepted; keystrokes arriving faster are refused
+    pub max_wpm: u32,
+    /// Laravel endpoint race results are posted to
+    pub results_url: Option<String>,
+    /// Key used to sign result payloads
+    pub results_secret: String,
+    /// Directory holding results not yet accepted by Laravel
+    pub results_outbox: PathBuf,
+    /// Directory the race text corpus is loaded from
+    pub texts_dir: PathBuf,
+    /// JSON file skill ratings are kept in
+    pub ratings_path: PathBuf,
+    /// Most a single race can move a rating
+    pub rating_k_factor: f64,
+    /// Fewest players a match may start with once `match_fill_wait` has passed
+    pub match_min_players: usize,
+    /// Players in a full match
+    pub match_max_players: usize,
+    /// How long to hold out for a full match before settling for fewer
+    pub match_fill_wait: Duration,
+    /// Rating difference allowed between newly queued players
+    pub match_rating_window: u32,
+    /// How much the rating window widens per second spent in the queue
+    pub match_window_growth: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let results_url = laravel_url("RESULTS_PATH", "/api/game-server/results");
+        let results_secret = env::var("RESULTS_HMAC_SECRET")
+            .or_else(|_| env::var("JWT_SECRET"))
+            .unwrap_or_default();
+        // Anyone could sign results with an empty key
+        if results_url.is_some() && results_secret.is_empty() {
+            panic!("RESULTS_HMAC_SECRET or JWT_SECRET must be set when LARAVEL_API_URL is");
+        }
+
+        Self {
+            reconnect_grace: Duration::from_secs(env_u64("RECONNECT_GRACE_SECONDS", 30)),
+            countdown: Duration::from_secs(env_u64("COUNTDOWN_SECONDS", 3)),
+            race_timeout: Duration::from_secs(env_u64("GAME_TIMEOUT_SECONDS", 300)),
+            lobby_timeout: Duration::from_secs(env_u64("LOBBY_TIMEOUT_SECONDS", 600)),
+            round_intermission: Duration::from_secs(env_u64("ROUND_INTERMISSION_SECONDS", 10)),
+            rematch_timeout: Duration::from_secs(env_u64("REMATCH_TIMEOUT_SECONDS", 120)),
+            max_latency_compensation: Duration::from_millis(env_u64("MAX_LATENCY_COMPENSATION_MS", 150)),
+            max_wpm: env_u64("MAX_WPM", 300) as u32,
+            results_url,
+            results_secret,
+            results_outbox: PathBuf::from(env::var("RESULTS_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string())),
+            texts_dir: PathBuf::from(env::var("TEXTS_DIR").unwrap_or_else(|_| "texts".to_string())),
+            ratings_path: PathBuf::from(env::var("RATINGS_PATH").unwrap_or_else(|_| "ratings.json".to_string())),
+            rating_k_factor: env_u64("RATING_K_FACTOR", 32) as f64,
+            match_min_players: env_u64("MATCH_MIN_PLAYERS", 2).max(1) as usize,
+            match_max_players: env_u64("MATCH_MAX_PLAYERS", 4).max(1) as usize,
+            match_fill_wait: Duration::from_secs(env_u64("MATCH_FILL_SECONDS", 10)),
+            match_rating_window: env_u64("MATCH_RATING_WINDOW", 100) as u32,
+            match_window_growth: env_u64("MATCH_WINDOW_GROWTH", 20) as u32,
+        }
+    }
+}
+
+/// `LARAVEL_API_URL` joined with the path in `path_key`, if Laravel is configured.
+fn laravel_url(path_key: &str, default_path: &str) -> Option<String> {
+    let base = env::var("LARAVEL_API_URL").ok()?;
+    let path = env::var(path_key).unwrap_or_else(|_| default_path.to_string());
+    Some(format!("{}{}", base.trim_end_matches('/'), path))
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a non-negative integer", key))
+        })
+        .unwrap_or(default)
+}
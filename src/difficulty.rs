@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How hard a text is to type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// Difficulty for an analyzer score between 0 and 100.
+    pub fn from_score(score: f64) -> Self {
+        if score < 25.0 {
+            Difficulty::Easy
+        } else if score < 45.0 {
+            Difficulty::Medium
+        } else {
+            Difficulty::Hard
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The most frequent letter pairs in English prose. Pairs outside this set
+/// make a text harder to type fluently.
+const COMMON_BIGRAMS: &[&str] = &[
+    "th", "he", "in", "er", "an", "re", "on", "at", "en", "nd", "ti", "es", "or", "te", "of", "ed", "is", "it",
+    "al", "ar", "st", "to", "nt", "ng", "se", "ha", "as", "ou", "io", "le", "ve", "co", "me", "de", "hi", "ri",
+    "ro", "ic", "ne", "ea", "ra", "ce", "li", "ch", "ll", "be", "ma", "si", "om", "ur", "ca", "el", "ta", "la",
+    "ns", "di", "fo", "ho", "pe", "ec", "pr", "no", "ct", "us", "ac", "ot", "il", "tr", "ly", "nc", "et", "ut",
+    "ss", "so", "rs", "un", "lo", "wa", "ge", "ie", "wh", "ee", "wi", "em", "ad", "ol", "rt", "po", "we", "na",
+    "ul", "ni", "ts", "mo", "ow", "pa", "im", "mi", "ai", "sh", "ir", "su", "id", "os", "iv", "ia", "am", "fi",
+    "ci", "vi", "pl", "ig", "tu", "ev", "ld", "ry", "mp", "fe", "bl", "ab", "gh", "ty", "op", "wo", "sa", "ay",
+    "ke", "oo", "ag", "gr", "od", "bo", "do", "ok", "ye", "yo", "da", "ck", "ga", "go", "ba", "ki",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Analysis {
+    /// Weighted total between 0 and 100
+    pub score: f64,
+    pub difficulty: Difficulty,
+}
+
+fn is_punctuation(c: &char) -> bool {
+    matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | '\'' | '"' | '-' | '(' | ')')
+}
+
+/// Score how hard `text` is to type from its word lengths and the share of
+/// punctuation, capitals, digits, uncommon letter pairs and symbols in it.
+pub fn analyze(text: &str) -> Analysis {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let total = chars.len().max(1) as f64;
+    let share = |test: fn(&char) -> bool| chars.iter().filter(|c| test(c)).count() as f64 / total;
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let average_word_length = chars.len() as f64 / words.len().max(1) as f64;
+    let punctuation = share(is_punctuation);
+    let digits = share(char::is_ascii_digit);
+    let symbols = share(|c| !c.is_alphanumeric() && !is_punctuation(c));
+
+    let letters: Vec<char> = chars.iter().copied().filter(|c| c.is_alphabetic()).collect();
+    let capitals = letters.iter().filter(|c| c.is_uppercase()).count() as f64 / letters.len().max(1) as f64;
+
+    let mut bigrams = 0;
+    let mut rare = 0;
+    for word in &words {
+        let lower: Vec<char> = word.chars().filter(|c| c.is_alphabetic()).flat_map(char::to_lowercase).collect();
+        for pair in lower.windows(2) {
+            bigrams += 1;
+            let pair: String = pair.iter().collect();
+            if !COMMON_BIGRAMS.contains(&pair.as_str()) {
+                rare += 1;
+            }
+        }
+    }
+    let rare_bigrams = rare as f64 / bigrams.max(1) as f64;
+
+    // Each feature is scaled so that 1.0 means "about as hard as it gets"
+    let score = 100.0
+        * (0.25 * ((average_word_length - 3.5) / 2.0).clamp(0.0, 1.0)
+            + 0.15 * (punctuation / 0.06).clamp(0.0, 1.0)
+            + 0.15 * (capitals / 0.1).clamp(0.0, 1.0)
+            + 0.15 * (digits / 0.05).clamp(0.0, 1.0)
+            + 0.15 * ((rare_bigrams - 0.05) / 0.35).clamp(0.0, 1.0)
+            + 0.15 * (symbols / 0.03).clamp(0.0, 1.0));
+
+    Analysis {
+        score,
+        difficulty: Difficulty::from_score(score),
+    }
+}
@@ -1,161 +1,1244 @@
-use crate::models::{GameStatus, Player, ServerMessage};
-use crate::state::AppState;
+use crate::auth::GameTicket;
+use crate::bots::{self, Bot};
+use crate::models::{GameError, GameState, GameStatus, Player, PlayerAction, ServerMessage};
+use crate::modes::GameMode;
+use crate::results::RaceResult;
+use crate::state::{AppState, Session, Tx};
+use dashmap::mapref::entry::Entry;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
 use tracing::info;
 
-pub async fn handle_player_join(
-    state: &AppState,
-    game_id: &str,
-    player: Player,
-) -> Result<ServerMessage, String> {
-    let game = state.update_game(game_id, |game| {
-        if !game.add_player(player.clone()) {
-            return;
-        }
-    });
+type Reply = oneshot::Sender<Result<(), GameError>>;
 
-    match game {
-        Some(game) => {
-            info!("Player {} joined game {}", player.username, game_id);
-            
-            // Broadcast to all players except the new one
-            let message = serde_json::to_string(&ServerMessage::PlayerJoined {
-                player: player.clone(),
-            })
-            .unwrap();
-            state.broadcast_to_game(game_id, &message, Some(&player.id));
-            
-            Ok(ServerMessage::GameState { game })
+/// Requests a connection can make of a game. Each game task handles them one
+/// at a time and in order, so every state transition is atomic.
+pub enum GameCommand {
+    Join {
+        player: Box<Player>,
+        /// `None` for players the server seats itself, e.g. through matchmaking
+        ticket: Option<Box<GameTicket>>,
+        /// Join as a spectator rather than a player
+        spectate: bool,
+        tx: Tx,
+        reply: Reply,
+    },
+    Resume {
+        player_id: String,
+        resume_token: String,
+        tx: Tx,
+        reply: Reply,
+    },
+    Leave {
+        player_id: String,
+    },
+    /// The socket `tx` belongs to has closed
+    Disconnect {
+        player_id: String,
+        tx: Tx,
+    },
+    StartGame {
+        player_id: String,
+    },
+    TransferHost {
+        player_id: String,
+        new_host_id: String,
+    },
+    AssignTeam {
+        player_id: String,
+        target_id: String,
+        team: u32,
+    },
+    AddBot {
+        player_id: String,
+        wpm: u32,
+    },
+    /// Remove `target_id` from the game, banning their user if `ban` is set
+    Kick {
+        player_id: String,
+        target_id: String,
+        ban: bool,
+    },
+    Keystrokes {
+        player_id: String,
+        keys: String,
+        received_at: i64,
+        typed_at: i64,
+    },
+    RequestRematch {
+        player_id: String,
+    },
+    /// Matchmaking has seated everyone it could; close the room and start
+    MatchSeated,
+}
+
+/// Counters a game publishes for metrics without a round trip to its task.
+#[derive(Default)]
+pub struct GameStats {
+    pub players: AtomicUsize,
+    pub connections: AtomicUsize,
+}
+
+/// Cheap, cloneable handle to a running game task.
+#[derive(Clone)]
+pub struct GameHandle {
+    commands: mpsc::UnboundedSender<GameCommand>,
+    pub stats: Arc<GameStats>,
+}
+
+impl GameHandle {
+    /// Queue a command. Returns `false` if the game has already ended.
+    pub fn send(&self, command: GameCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// Queue a command and wait for the game's answer.
+    pub async fn request<F>(&self, command: F) -> Result<(), GameError>
+    where
+        F: FnOnce(Reply) -> GameCommand,
+    {
+        let (reply, answer) = oneshot::channel();
+        if !self.send(command(reply)) {
+            return Err(GameError::GameNotFound);
         }
-        None => Err("Game not found".to_string()),
+        answer.await.unwrap_or(Err(GameError::GameNotFound))
     }
 }
 
-pub async fn handle_player_leave(state: &AppState, game_id: &str, player_id: &str) {
-    state.update_game(game_id, |game| {
-        game.remove_player(player_id);
-    });
-
-    let message = serde_json::to_string(&ServerMessage::PlayerLeft {
-        player_id: player_id.to_string(),
-    })
-    .unwrap();
-    
-    state.broadcast_to_game(game_id, &message, None);
-    info!("Player {} left game {}", player_id, game_id);
-    
-    // Check if game is empty or finished, clean it up
-    if let Some(game) = state.get_game(game_id) {
-        if game.players.is_empty() || game.status == GameStatus::Finished {
-            state.remove_game(game_id);
-            info!("Game {} removed (empty or finished)", game_id);
+/// Start a task owning `game` and register its handle. If a game with the
+/// same id appeared in the meantime, that game's handle is returned instead.
+pub fn create_game(state: &AppState, game: GameState) -> GameHandle {
+    match state.games.entry(game.id.clone()) {
+        Entry::Occupied(existing) => existing.get().clone(),
+        Entry::Vacant(slot) => {
+            let (commands, rx) = mpsc::unbounded_channel();
+            let handle = GameHandle {
+                commands,
+                stats: Arc::new(GameStats::default()),
+            };
+            slot.insert(handle.clone());
+
+            info!("Created game {} in state", game.id);
+            let actor = GameActor::new(state.clone(), game, handle.stats.clone());
+            tokio::spawn(actor.run(rx, handle.commands.clone()));
+            handle
         }
     }
 }
 
-pub async fn handle_start_game(state: &AppState, game_id: &str, player_id: &str) -> Result<(), String> {
-    let game = state.get_game(game_id).ok_or("Game not found")?;
-    
-    // Only host can start the game
-    if game.host_id != player_id {
-        return Err("Only the host can start the game".to_string());
-    }
-    
-    if game.status != GameStatus::Waiting {
-        return Err("Game already started".to_string());
-    }
-    
-    let started_at = chrono::Utc::now().timestamp();
-    
-    state.update_game(game_id, |game| {
-        game.start_game();
-    });
-    
-    let message = serde_json::to_string(&ServerMessage::GameStarted { started_at }).unwrap();
-    state.broadcast_to_game(game_id, &message, None);
-    
-    info!("Game {} started by {}", game_id, player_id);
-    Ok(())
+enum Timer {
+    CountdownTick,
+    RaceTimeout { started_at: i64 },
+    /// The race mode's periodic hook
+    ModeTick { started_at: i64 },
+    BotTyping { player_id: String, started_at: i64 },
+    LobbyTimeout { lobby: u32 },
+    NextRound { round: u32 },
+    RematchTimeout { started_at: Option<i64> },
+    GraceExpired { player_id: String, disconnected_at: i64 },
 }
 
-pub async fn handle_update_progress(
-    state: &AppState,
-    game_id: &str,
-    player_id: &str,
-    progress: u32,
-    wpm: u32,
-    accuracy: f32,
-) -> Result<(), String> {
-    state.update_game(game_id, |game| {
-        if let Some(player) = game.get_player_mut(player_id) {
-            player.progress = progress;
-            player.wpm = wpm;
-            player.accuracy = accuracy;
-        }
-    });
-    
-    let message = serde_json::to_string(&ServerMessage::PlayerProgress {
-        player_id: player_id.to_string(),
-        progress,
-        wpm,
-        accuracy,
-    })
-    .unwrap();
-    
-    state.broadcast_to_game(game_id, &message, Some(player_id));
-    Ok(())
+/// The task that owns one game's state and the sockets of its players.
+struct GameActor {
+    state: AppState,
+    game: GameState,
+    /// The rules of the game's race mode
+    rules: Box<dyn GameMode>,
+    /// Seated bots by player id
+    bots: HashMap<String, Bot>,
+    /// Bots ever added, so a new bot never reuses a removed one's name
+    bots_added: usize,
+    connections: HashMap<String, Tx>,
+    /// Sockets that receive broadcasts without a seat in the race
+    spectators: HashMap<String, Tx>,
+    timers: Vec<(Instant, Timer)>,
+    stats: Arc<GameStats>,
+    /// Bumped on every rematch so an earlier lobby's timeout is ignored
+    lobby: u32,
+    /// Players who voted to race again since the game finished
+    rematch_votes: HashSet<String>,
+    /// Characters of the text sent to spectators, who follow the furthest player
+    spectators_revealed: usize,
+    closed: bool,
 }
 
-pub async fn handle_finish_game(
-    state: &AppState,
-    game_id: &str,
-    player_id: &str,
-    wpm: u32,
-    accuracy: f32,
-) -> Result<(), String> {
-    let finished_at = chrono::Utc::now().timestamp();
-    
-    let all_finished = state.update_game(game_id, |game| {
-        if let Some(player) = game.get_player_mut(player_id) {
-            player.finished = true;
-            player.finished_at = Some(finished_at);
-            player.wpm = wpm;
-            player.accuracy = accuracy;
-        }
-    })
-    .map(|game| game.check_all_finished())
-    .unwrap_or(false);
-    
-    // Broadcast player finished
-    let message = serde_json::to_string(&ServerMessage::PlayerFinished {
-        player_id: player_id.to_string(),
-        wpm,
-        accuracy,
-        finished_at,
-    })
-    .unwrap();
-    state.broadcast_to_game(game_id, &message, None);
-    
-    // If all players finished, end the game
-    if all_finished {
-        if let Some(game) = state.get_game(game_id) {
-            state.update_game(game_id, |g| {
-                g.status = GameStatus::Finished;
-            });
-            
-            let winner_id = game.get_winner();
-            let mut final_standings = game.players.clone();
-            final_standings.sort_by_key(|p| p.finished_at.unwrap_or(i64::MAX));
-            
-            let message = serde_json::to_string(&ServerMessage::GameFinished {
-                winner_id,
-                final_standings,
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl GameActor {
+    fn new(state: AppState, game: GameState, stats: Arc<GameStats>) -> Self {
+        Self {
+            state,
+            rules: game.settings.mode.rules(),
+            bots: HashMap::new(),
+            bots_added: 0,
+            game,
+            connections: HashMap::new(),
+            spectators: HashMap::new(),
+            timers: Vec::new(),
+            stats,
+            lobby: 0,
+            rematch_votes: HashSet::new(),
+            spectators_revealed: 0,
+            closed: false,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<GameCommand>,
+        commands: mpsc::UnboundedSender<GameCommand>,
+    ) {
+        self.schedule(self.state.config.lobby_timeout, Timer::LobbyTimeout { lobby: self.lobby });
+
+        while !self.closed {
+            let next_timer = self.timers.iter().map(|(at, _)| *at).min();
+            tokio::select! {
+                command = rx.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                _ = sleep_until(next_timer) => self.fire_due_timers().await,
+            }
+            self.publish_stats();
+        }
+
+        // Unregister before dropping `rx`, so anything still queued gets "Game not found"
+        self.state
+            .games
+            .remove_if(&self.game.id, |_, handle| handle.commands.same_channel(&commands));
+        self.state.remove_sessions_for_game(&self.game.id);
+    }
+
+    async fn handle_command(&mut self, command: GameCommand) {
+        match command {
+            GameCommand::Join { player, ticket, spectate: false, tx, reply } => {
+                let _ = reply.send(self.handle_player_join(*player, ticket.as_deref(), tx));
+            }
+            GameCommand::Join { player, ticket, spectate: true, tx, reply } => {
+                let _ = reply.send(self.handle_spectator_join(&player, ticket.as_deref(), tx));
+            }
+            GameCommand::Resume { player_id, resume_token, tx, reply } => {
+                let _ = reply.send(self.handle_player_resume(&player_id, resume_token, tx));
+            }
+            GameCommand::Leave { player_id } => self.handle_player_leave(&player_id).await,
+            GameCommand::Disconnect { player_id, tx } => self.handle_player_disconnect(&player_id, &tx).await,
+            GameCommand::StartGame { player_id } => {
+                if let Err(e) = self.handle_start_game(&player_id) {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::TransferHost { player_id, new_host_id } => {
+                if let Err(e) = self.handle_transfer_host(&player_id, &new_host_id) {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::AssignTeam {
+                player_id,
+                target_id,
+                team,
+            } => {
+                if let Err(e) = self.handle_assign_team(&player_id, &target_id, team) {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::AddBot { player_id, wpm } => {
+                if let Err(e) = self.handle_add_bot(&player_id, wpm) {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::Kick { player_id, target_id, ban } => {
+                if let Err(e) = self.handle_kick(&player_id, &target_id, ban).await {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::Keystrokes { player_id, keys, received_at, typed_at } => {
+                if let Err(e) = self.handle_keystrokes(&player_id, &keys, received_at, typed_at).await {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::RequestRematch { player_id } => {
+                if let Err(e) = self.handle_request_rematch(&player_id) {
+                    self.send_error(&player_id, e);
+                }
+            }
+            GameCommand::MatchSeated => self.handle_match_seated(),
+        }
+    }
+
+    fn schedule(&mut self, after: Duration, timer: Timer) {
+        self.timers.push((Instant::now() + after, timer));
+    }
+
+    async fn fire_due_timers(&mut self) {
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.timers)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.timers = pending;
+
+        for (_, timer) in due {
+            self.on_timer(timer).await;
+        }
+    }
+
+    async fn on_timer(&mut self, timer: Timer) {
+        match timer {
+            Timer::CountdownTick => self.countdown_tick(),
+            Timer::RaceTimeout { started_at } => {
+                // Make sure this is still the race the timer was started for
+                if self.game.status == GameStatus::Playing && self.game.started_at == Some(started_at) {
+                    info!("Game {} hit the race time limit", self.game.id);
+                    self.finish_game().await;
+                }
+            }
+            Timer::ModeTick { started_at } => {
+                if self.game.status == GameStatus::Playing && self.game.started_at == Some(started_at) {
+                    self.mode_tick(started_at).await;
+                }
+            }
+            Timer::BotTyping { player_id, started_at } => {
+                if self.game.status == GameStatus::Playing && self.game.started_at == Some(started_at) {
+                    self.bot_tick(player_id, started_at).await;
+                }
+            }
+            Timer::NextRound { round } => {
+                if self.game.status == GameStatus::Intermission && self.game.round == round {
+                    self.start_next_round();
+                }
+            }
+            Timer::LobbyTimeout { lobby } => {
+                if self.game.status == GameStatus::Waiting && lobby == self.lobby {
+                    info!("Game {} timed out in the lobby", self.game.id);
+                    self.close_game("Lobby timed out");
+                }
+            }
+            Timer::RematchTimeout { started_at } => {
+                if self.game.status == GameStatus::Finished && self.game.started_at == started_at {
+                    info!("Game {} closed without a rematch", self.game.id);
+                    self.close_game("No rematch");
+                }
+            }
+            Timer::GraceExpired { player_id, disconnected_at } => {
+                // Only remove the player if they have not resumed (or dropped again) since
+                let still_away = self
+                    .game
+                    .players
+                    .iter()
+                    .any(|p| p.id == player_id && p.disconnected_at == Some(disconnected_at));
+                if still_away {
+                    info!("Grace period expired for player {} in game {}", player_id, self.game.id);
+                    self.handle_player_leave(&player_id).await;
+                }
+            }
+        }
+    }
+
+    fn publish_stats(&self) {
+        self.stats.players.store(self.game.players.len(), Ordering::Relaxed);
+        self.stats
+            .connections
+            .store(self.connections.len() + self.spectators.len(), Ordering::Relaxed);
+    }
+
+    fn send_to(&self, player_id: &str, message: &ServerMessage) {
+        if let Some(tx) = self.connections.get(player_id).or_else(|| self.spectators.get(player_id)) {
+            let _ = tx.send(Message::Text(serde_json::to_string(message).unwrap()));
+        }
+    }
+
+    fn send_error(&self, player_id: &str, error: GameError) {
+        self.send_to(player_id, &error.into());
+    }
+
+    fn broadcast(&self, message: &ServerMessage, exclude_player: Option<&str>) {
+        let message = serde_json::to_string(message).unwrap();
+        for (player_id, tx) in self.connections.iter().chain(&self.spectators) {
+            if exclude_player == Some(player_id.as_str()) {
+                continue;
+            }
+            let _ = tx.send(Message::Text(message.clone()));
+        }
+    }
+
+    /// Stop the game task once the current command has been handled.
+    fn close(&mut self) {
+        self.closed = true;
+        info!("Game {} removed", self.game.id);
+    }
+
+    /// Abort the game, tell everyone in it that it is gone, then stop.
+    fn close_game(&mut self, reason: &str) {
+        let _ = self.game.abort();
+        self.broadcast(
+            &ServerMessage::GameClosed {
+                reason: reason.to_string(),
+            },
+            None,
+        );
+        self.close();
+    }
+
+    fn handle_player_join(
+        &mut self,
+        player: Player,
+        ticket: Option<&GameTicket>,
+        tx: Tx,
+    ) -> Result<(), GameError> {
+        if let Some(ticket) = ticket {
+            ticket
+                .check(&player.user_id, &self.game)
+                .map_err(GameError::InvalidTicket)?;
+        }
+        self.game.status.check(PlayerAction::Join)?;
+
+        self.game.add_player(player, ticket.is_some())?;
+        let racing = self.game.status == GameStatus::Playing;
+        let seated = self.game.players.last_mut().ok_or(GameError::PlayerNotFound)?;
+        // Late joiners are timed from when they sit down, not from the race start
+        if racing {
+            seated.typing.start(chrono::Utc::now().timestamp_millis());
+        }
+        // Seating may have put the player on a team
+        let player = seated.clone();
+        self.connections.insert(player.id.clone(), tx);
+        info!("Player {} joined game {}", player.username, self.game.id);
+
+        let resume_token = self.state.create_session(Session {
+            player_id: player.id.clone(),
+            user_id: player.user_id.clone(),
+            game_id: self.game.id.clone(),
+        });
+        self.send_to(
+            &player.id,
+            &ServerMessage::Connected {
+                player_id: player.id.clone(),
+                resume_token,
+            },
+        );
+        self.send_to(&player.id, &ServerMessage::GameState { game: self.game.clone() });
+        if self.game.status == GameStatus::Playing {
+            self.reveal_text(&player.id);
+        }
+
+        // Broadcast to all players except the new one
+        self.broadcast(&ServerMessage::PlayerJoined { player: player.clone() }, Some(&player.id));
+
+        let full = self.game.players.len() >= self.game.settings.max_players;
+        if self.game.settings.auto_start && full && self.start_countdown().is_ok() {
+            info!("Game {} is full, starting automatically", self.game.id);
+        }
+        Ok(())
+    }
+
+    fn handle_spectator_join(
+        &mut self,
+        spectator: &Player,
+        ticket: Option<&GameTicket>,
+        tx: Tx,
+    ) -> Result<(), GameError> {
+        if let Some(ticket) = ticket {
+            ticket
+                .check(&spectator.user_id, &self.game)
+                .map_err(GameError::InvalidTicket)?;
+        }
+        self.game.status.check(PlayerAction::Spectate)?;
+        self.game.check_access(&spectator.user_id, ticket.is_some())?;
+
+        self.spectators.insert(spectator.id.clone(), tx);
+        self.game.spectators = self.spectators.len();
+        info!("{} is spectating game {}", spectator.username, self.game.id);
+
+        self.send_to(
+            &spectator.id,
+            &ServerMessage::Spectating {
+                spectator_id: spectator.id.clone(),
+            },
+        );
+        self.send_to(&spectator.id, &ServerMessage::GameState { game: self.game.clone() });
+        if self.spectators_revealed > 0 {
+            self.send_text(&spectator.id, self.spectators_revealed);
+        }
+        self.broadcast_spectator_count();
+        Ok(())
+    }
+
+    /// Spectators hold no seat, so leaving and disconnecting are the same.
+    fn remove_spectator(&mut self, spectator_id: &str) {
+        self.spectators.remove(spectator_id);
+        self.game.spectators = self.spectators.len();
+        info!("Spectator {} left game {}", spectator_id, self.game.id);
+        self.broadcast_spectator_count();
+    }
+
+    fn broadcast_spectator_count(&self) {
+        self.broadcast(
+            &ServerMessage::SpectatorsChanged {
+                spectators: self.game.spectators,
+            },
+            None,
+        );
+    }
+
+    async fn handle_player_leave(&mut self, player_id: &str) {
+        if self.spectators.contains_key(player_id) {
+            self.remove_spectator(player_id);
+            return;
+        }
+        let was_host = self.game.is_host(player_id);
+        self.game.remove_player(player_id);
+        self.bots.remove(player_id);
+        self.connections.remove(player_id);
+        self.state.remove_sessions_for_player(player_id);
+
+        self.broadcast(
+            &ServerMessage::PlayerLeft {
+                player_id: player_id.to_string(),
+            },
+            None,
+        );
+        info!("Player {} left game {}", player_id, self.game.id);
+
+        // Pass the host role on so the game can still be started
+        if was_host {
+            if let Some(next_host) = self.game.next_host().map(|p| p.id.clone()) {
+                self.set_host(&next_host);
+            }
+        }
+
+        // The leaver may have been the last one still typing
+        if self.game.status == GameStatus::Playing && self.rules.is_over(&self.game) {
+            self.finish_game().await;
+        }
+
+        // Check if game is empty (bots do not keep it open), clean it up
+        if self.game.players.iter().all(|p| p.bot) {
+            // Nobody is left to finish the race; let any spectators know
+            self.close_game("All players left");
+            return;
+        }
+
+        // Fewer players may now be enough to carry the rematch vote
+        if self.game.status == GameStatus::Finished {
+            self.rematch_votes.remove(player_id);
+            self.try_rematch();
+        }
+    }
+
+    /// Hold a dropped player's seat for the reconnect grace period before removing them.
+    async fn handle_player_disconnect(&mut self, player_id: &str, tx: &Tx) {
+        if self.spectators.get(player_id).is_some_and(|current| current.same_channel(tx)) {
+            self.remove_spectator(player_id);
+            return;
+        }
+
+        // A resumed session already moved the player to another socket
+        if !self.connections.get(player_id).is_some_and(|current| current.same_channel(tx)) {
+            return;
+        }
+        self.connections.remove(player_id);
+
+        let grace = self.state.config.reconnect_grace;
+        if grace.is_zero() {
+            self.handle_player_leave(player_id).await;
+            return;
+        }
+
+        let disconnected_at = chrono::Utc::now().timestamp_millis();
+        if let Some(player) = self.game.get_player_mut(player_id) {
+            player.connected = false;
+            player.disconnected_at = Some(disconnected_at);
+        }
+
+        self.broadcast(
+            &ServerMessage::PlayerDisconnected {
+                player_id: player_id.to_string(),
+            },
+            None,
+        );
+        info!(
+            "Player {} disconnected from game {}, holding seat for {:?}",
+            player_id, self.game.id, grace
+        );
+
+        self.schedule(
+            grace,
+            Timer::GraceExpired {
+                player_id: player_id.to_string(),
+                disconnected_at,
+            },
+        );
+
+        // Nobody could start the lobby while its host's seat is held for them
+        let hosting = self.game.players.iter().any(|p| p.id == player_id && p.user_id == self.game.host_id);
+        if hosting && self.game.status == GameStatus::Waiting {
+            let next_host = self.game.next_host().filter(|p| p.connected).map(|p| p.id.clone());
+            if let Some(next_host) = next_host {
+                self.set_host(&next_host);
+            }
+        }
+
+        if self.game.status == GameStatus::Finished {
+            self.try_rematch();
+        }
+    }
+
+    fn handle_player_resume(&mut self, player_id: &str, resume_token: String, tx: Tx) -> Result<(), GameError> {
+        let player = self.game.get_player_mut(player_id).ok_or(GameError::SessionExpired)?;
+        player.connected = true;
+        player.disconnected_at = None;
+        let revealed = player.revealed;
+
+        // Replace whatever socket the player had before
+        self.connections.insert(player_id.to_string(), tx);
+        info!("Player {} resumed in game {}", player_id, self.game.id);
+
+        self.send_to(
+            player_id,
+            &ServerMessage::Connected {
+                player_id: player_id.to_string(),
+                resume_token,
+            },
+        );
+        self.send_to(player_id, &ServerMessage::GameState { game: self.game.clone() });
+        if revealed > 0 {
+            self.send_text(player_id, revealed);
+        }
+        self.broadcast(
+            &ServerMessage::PlayerReconnected {
+                player_id: player_id.to_string(),
+            },
+            Some(player_id),
+        );
+        Ok(())
+    }
+
+    fn handle_start_game(&mut self, player_id: &str) -> Result<(), GameError> {
+        // Only host can start the game
+        let player = self.game.players.iter().find(|p| p.id == player_id).ok_or(GameError::PlayerNotFound)?;
+        if self.game.host_id != player.user_id {
+            return Err(GameError::NotHost);
+        }
+        self.game.status.check(PlayerAction::StartGame)?;
+
+        self.start_countdown()?;
+        info!("Game {} countdown started by {}", self.game.id, player_id);
+        Ok(())
+    }
+
+    fn start_countdown(&mut self) -> Result<(), GameError> {
+        let start_at = chrono::Utc::now().timestamp_millis() + self.state.config.countdown.as_millis() as i64;
+        self.game.start_countdown(start_at)?;
+        self.broadcast(&ServerMessage::CountdownStarted { start_at }, None);
+
+        self.countdown_tick();
+        Ok(())
+    }
+
+    /// Matched users who dropped before they were seated never arrive, so the
+    /// room is sized to, and hosted by, whoever did.
+    fn handle_match_seated(&mut self) {
+        if self.game.players.is_empty() {
+            return;
+        }
+        self.game.settings.max_players = self.game.players.len();
+        self.game.settings.allowed_user_ids = self.game.players.iter().map(|p| p.user_id.clone()).collect();
+        if !self.game.players.iter().any(|p| p.user_id == self.game.host_id) {
+            let first = self.game.players[0].id.clone();
+            self.set_host(&first);
+        }
+
+        if self.start_countdown().is_ok() {
+            info!("Game {} is seated, starting the match", self.game.id);
+        }
+    }
+
+    fn handle_transfer_host(&mut self, player_id: &str, new_host_id: &str) -> Result<(), GameError> {
+        self.game.status.check(PlayerAction::TransferHost)?;
+        if !self.game.is_host(player_id) {
+            return Err(GameError::NotHost);
+        }
+        if !self.game.players.iter().any(|p| p.id == new_host_id && !p.bot) {
+            return Err(GameError::PlayerNotFound);
+        }
+
+        self.set_host(new_host_id);
+        Ok(())
+    }
+
+    fn handle_assign_team(&mut self, player_id: &str, target_id: &str, team: u32) -> Result<(), GameError> {
+        self.game.status.check(PlayerAction::AssignTeam)?;
+        if !self.game.is_host(player_id) {
+            return Err(GameError::NotHost);
+        }
+        self.game.assign_team(target_id, team)?;
+
+        self.broadcast(
+            &ServerMessage::TeamChanged {
+                player_id: target_id.to_string(),
+                team,
+            },
+            None,
+        );
+        info!("Player {} moved to team {} in game {}", target_id, team, self.game.id);
+        Ok(())
+    }
+
+    fn handle_add_bot(&mut self, player_id: &str, wpm: u32) -> Result<(), GameError> {
+        self.game.status.check(PlayerAction::AddBot)?;
+        if !self.game.is_host(player_id) {
+            return Err(GameError::NotHost);
+        }
+
+        let bot = Bot::new(wpm, self.state.config.max_wpm);
+        self.game.add_bot(bot.player(self.bots_added + 1))?;
+        self.bots_added += 1;
+        let player = self.game.players.last().cloned().ok_or(GameError::PlayerNotFound)?;
+        self.bots.insert(player.id.clone(), bot);
+
+        self.broadcast(&ServerMessage::PlayerJoined { player: player.clone() }, None);
+        info!("Player {} added bot {} to game {}", player_id, player.id, self.game.id);
+
+        let full = self.game.players.len() >= self.game.settings.max_players;
+        if self.game.settings.auto_start && full && self.start_countdown().is_ok() {
+            info!("Game {} is full, starting automatically", self.game.id);
+        }
+        Ok(())
+    }
+
+    async fn handle_kick(&mut self, player_id: &str, target_id: &str, ban: bool) -> Result<(), GameError> {
+        self.game.status.check(PlayerAction::Kick)?;
+        if !self.game.is_host(player_id) {
+            return Err(GameError::NotHost);
+        }
+        if player_id == target_id {
+            return Err(GameError::CannotTargetSelf);
+        }
+        let target = self
+            .game
+            .players
+            .iter()
+            .find(|p| p.id == target_id)
+            .ok_or(GameError::PlayerNotFound)?;
+
+        if ban {
+            self.game.banned_user_ids.insert(target.user_id.clone());
+        }
+        self.broadcast(
+            &ServerMessage::PlayerKicked {
+                player_id: target_id.to_string(),
+                banned: ban,
+            },
+            None,
+        );
+        info!(
+            "Player {} was {} from game {} by {}",
+            target_id,
+            if ban { "banned" } else { "kicked" },
+            self.game.id,
+            player_id
+        );
+
+        // Close their socket; its disconnect is ignored once the connection is gone
+        if let Some(tx) = self.connections.remove(target_id) {
+            let _ = tx.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: if ban { "Banned by host" } else { "Kicked by host" }.into(),
+            })));
+        }
+        self.handle_player_leave(target_id).await;
+        Ok(())
+    }
+
+    fn set_host(&mut self, player_id: &str) {
+        let Some(player) = self.game.players.iter().find(|p| p.id == player_id) else {
+            return;
+        };
+        self.game.host_id = player.user_id.clone();
+
+        self.broadcast(
+            &ServerMessage::HostChanged {
+                host_id: self.game.host_id.clone(),
+                player_id: player_id.to_string(),
+            },
+            None,
+        );
+        info!("Player {} is now host of game {}", player_id, self.game.id);
+    }
+
+    /// Tick down to the scheduled start once per second, then flip the game to `Playing`.
+    fn countdown_tick(&mut self) {
+        let (GameStatus::Countdown, Some(start_at)) = (&self.game.status, self.game.started_at) else {
+            return;
+        };
+
+        let remaining_ms = start_at - chrono::Utc::now().timestamp_millis();
+        if remaining_ms > 0 {
+            let seconds_remaining = (remaining_ms + 999) / 1000;
+            self.broadcast(&ServerMessage::CountdownTick { seconds_remaining }, None);
+
+            // Wake on the next whole-second boundary before the start
+            let until_next_tick = remaining_ms - (seconds_remaining - 1) * 1000;
+            self.schedule(Duration::from_millis(until_next_tick as u64), Timer::CountdownTick);
+            return;
+        }
+
+        if self.game.start_game().is_err() {
+            return;
+        }
+        self.broadcast(&ServerMessage::GameStarted { started_at: start_at }, None);
+        info!("Game {} started", self.game.id);
+
+        let player_ids: Vec<String> = self.game.players.iter().map(|p| p.id.clone()).collect();
+        for player_id in player_ids {
+            self.reveal_text(&player_id);
+        }
+
+        // End the race when the mode's time limit runs out, whoever is still typing
+        let timeout = self.rules.on_start(&mut self.game, &self.state.config);
+        self.schedule(timeout, Timer::RaceTimeout { started_at: start_at });
+        if let Some(interval) = self.rules.tick_interval() {
+            self.schedule(interval, Timer::ModeTick { started_at: start_at });
+        }
+        let bot_ids: Vec<String> = self.bots.keys().cloned().collect();
+        for player_id in bot_ids {
+            self.schedule(
+                bots::TICK,
+                Timer::BotTyping {
+                    player_id,
+                    started_at: start_at,
+                },
+            );
+        }
+    }
+
+    /// Type the next few characters for a bot, the same way a player's
+    /// keystrokes are handled.
+    async fn bot_tick(&mut self, player_id: String, started_at: i64) {
+        let segment = self.rules.segment(&self.game, &player_id);
+        let text: Vec<char> = self.game.text.chars().skip(segment.start).take(segment.len()).collect();
+        let Some(player) = self.game.players.iter().find(|p| p.id == player_id) else {
+            return;
+        };
+        if player.finished || player.eliminated_at.is_some() {
+            return;
+        }
+        let position = player.typing.correct_chars(&text);
+        let Some(bot) = self.bots.get_mut(&player_id) else {
+            return;
+        };
+
+        let keys = bot.keys(&text, position);
+        if !keys.is_empty() {
+            let now = chrono::Utc::now().timestamp_millis();
+            // A relay bot is refused until its teammate finishes; it just tries again
+            let _ = self.handle_keystrokes(&player_id, &keys, now, now).await;
+        }
+
+        if self.game.status == GameStatus::Playing && self.game.started_at == Some(started_at) {
+            self.schedule(bots::TICK, Timer::BotTyping { player_id, started_at });
+        }
+    }
+
+    /// Run the mode's periodic hook and schedule the next one unless that
+    /// ended the race.
+    async fn mode_tick(&mut self, started_at: i64) {
+        let now = chrono::Utc::now().timestamp_millis();
+        for message in self.rules.on_tick(&mut self.game, now) {
+            if let ServerMessage::Eliminated { player_id, .. } = &message {
+                info!("Player {} eliminated from game {}", player_id, self.game.id);
+            }
+            self.broadcast(&message, None);
+        }
+
+        if self.rules.is_over(&self.game) {
+            self.finish_game().await;
+        } else if let Some(interval) = self.rules.tick_interval() {
+            self.schedule(interval, Timer::ModeTick { started_at });
+        }
+    }
+
+    /// `typed_at` is when the keystrokes were typed, estimated from the
+    /// player's measured latency.
+    async fn handle_keystrokes(
+        &mut self,
+        player_id: &str,
+        keys: &str,
+        received_at: i64,
+        typed_at: i64,
+    ) -> Result<(), GameError> {
+        // The start tick may still be queued behind these keystrokes
+        let start_due = self.game.started_at.is_some_and(|start_at| received_at >= start_at);
+        if self.game.status == GameStatus::Countdown && start_due {
+            self.countdown_tick();
+        }
+        self.game.status.check(PlayerAction::Keystrokes)?;
+
+        let started_at = match self.game.started_at {
+            Some(started_at) if received_at >= started_at => started_at,
+            _ => return Err(GameError::RaceNotStarted),
+        };
+        // Relay legs only type their own part of the text
+        self.rules.check_turn(&self.game, player_id)?;
+        let segment = self.rules.segment(&self.game, player_id);
+        let text: Vec<char> = self.game.text.chars().skip(segment.start).take(segment.len()).collect();
+        let max_wpm = self.state.config.max_wpm;
+
+        let player = self.game.get_player_mut(player_id).ok_or(GameError::PlayerNotFound)?;
+        if player.finished {
+            return Err(GameError::AlreadyFinished);
+        }
+        if player.eliminated_at.is_some() {
+            return Err(GameError::Eliminated);
+        }
+        // Pasted or scripted input would otherwise set any WPM it liked
+        if player.typing.too_fast(keys, typed_at, max_wpm) {
+            return Err(GameError::TypingTooFast);
+        }
+
+        player.typing.apply(&text, keys);
+        player.progress = player.typing.progress(&text);
+        player.errors = player.typing.errors();
+        let started_at = player.typing.started_at().unwrap_or(started_at);
+        player.wpm = player.typing.wpm(&text, typed_at - started_at);
+        player.accuracy = player.typing.accuracy();
+
+        let progress = ServerMessage::PlayerProgress {
+            player_id: player_id.to_string(),
+            progress: player.progress,
+            wpm: player.wpm,
+            accuracy: player.accuracy,
+            errors: player.errors,
+        };
+        let completed = player.typing.is_complete(&text);
+
+        // The sender also needs the server's view of its own stats
+        self.broadcast(&progress, None);
+
+        for message in self.rules.on_progress(&mut self.game, player_id, typed_at) {
+            self.broadcast(&message, None);
+        }
+        let eliminated = self.game.players.iter().any(|p| p.id == player_id && p.eliminated_at.is_some());
+        if eliminated {
+            info!("Player {} eliminated from game {}", player_id, self.game.id);
+            if self.rules.is_over(&self.game) {
+                self.finish_game().await;
+            }
+            return Ok(());
+        }
+
+        self.reveal_text(player_id);
+
+        if completed {
+            self.handle_finish_game(player_id, typed_at).await;
+        }
+        Ok(())
+    }
+
+    /// Send a player whatever part of the text their progress has unlocked
+    /// since the last reveal.
+    fn reveal_text(&mut self, player_id: &str) {
+        let text: Vec<char> = self.game.text.chars().collect();
+        let reveal = self.game.settings.text_reveal;
+        let segment = self.rules.segment(&self.game, player_id);
+        let Some(player) = self.game.get_player_mut(player_id) else {
+            return;
+        };
+        let part = &text[segment.clone()];
+        let visible = segment.start + reveal.visible(part, player.typing.correct_chars(part));
+        if visible <= player.revealed {
+            return;
+        }
+        player.revealed = visible;
+        self.send_text(player_id, visible);
+
+        if visible > self.spectators_revealed {
+            self.spectators_revealed = visible;
+            for spectator_id in self.spectators.keys() {
+                self.send_text(spectator_id, visible);
+            }
+        }
+    }
+
+    fn send_text(&self, to: &str, revealed: usize) {
+        let message = ServerMessage::TextRevealed {
+            text_id: self.game.text_id.clone(),
+            text: self.game.text.chars().take(revealed).collect(),
+            source: self.game.text_source.clone(),
+            complete: revealed >= self.game.text_length,
+        };
+        self.send_to(to, &message);
+    }
+
+    /// Mark a player as finished once the server has seen them type the whole text.
+    /// `finished_at` is the latency-compensated time the last keystroke was typed.
+    async fn handle_finish_game(&mut self, player_id: &str, finished_at: i64) {
+        let Some(player) = self.game.get_player_mut(player_id) else {
+            return;
+        };
+        player.finished = true;
+        player.finished_at = Some(finished_at);
+
+        let message = ServerMessage::PlayerFinished {
+            player_id: player_id.to_string(),
+            wpm: player.wpm,
+            accuracy: player.accuracy,
+            finished_at,
+        };
+        self.broadcast(&message, None);
+
+        // If all players finished, end the game
+        if self.rules.is_over(&self.game) {
+            self.finish_game().await;
+        }
+    }
+
+    /// End the race: the mode settles who did not finish and ranks everyone,
+    /// then the standings are broadcast and reported. In a match every race
+    /// is a round and the game only finishes after the last one.
+    async fn finish_game(&mut self) {
+        let last_round = self.game.is_last_round();
+        let ended = if last_round {
+            self.game.finish()
+        } else {
+            self.game.finish_round()
+        };
+        if ended.is_err() {
+            return;
+        }
+        self.rules.on_finish(&mut self.game);
+
+        let mut standings = self.rules.rank(&self.game.field());
+        let mut winner_id = self.rules.winner(&standings);
+
+        if let Some(reporter) = &self.state.reporter {
+            reporter.report(RaceResult::new(&self.game, winner_id.clone(), &standings)).await;
+        }
+
+        // Anyone who did not get to the end may now read the rest
+        let length = self.game.text_length;
+        let mut behind: Vec<String> = self
+            .game
+            .players
+            .iter_mut()
+            .filter(|p| p.revealed < length)
+            .map(|p| {
+                p.revealed = length;
+                p.id.clone()
             })
-            .unwrap();
-            state.broadcast_to_game(game_id, &message, None);
-            
-            info!("Game {} finished", game_id);
+            .collect();
+        if self.spectators_revealed < length {
+            self.spectators_revealed = length;
+            behind.extend(self.spectators.keys().cloned());
+        }
+        for id in &behind {
+            self.send_text(id, length);
+        }
+
+        self.game.award_points(&mut standings);
+        if self.game.settings.rounds > 1 {
+            let next_round_at = (!last_round)
+                .then(|| chrono::Utc::now().timestamp_millis() + self.state.config.round_intermission.as_millis() as i64);
+            self.broadcast(
+                &ServerMessage::RoundFinished {
+                    round: self.game.round,
+                    rounds: self.game.settings.rounds,
+                    winner_id: winner_id.clone(),
+                    standings: standings.clone(),
+                    next_round_at,
+                },
+                None,
+            );
+            info!("Game {} finished round {}", self.game.id, self.game.round);
+
+            if !last_round {
+                self.schedule(
+                    self.state.config.round_intermission,
+                    Timer::NextRound { round: self.game.round },
+                );
+                return;
+            }
+
+            // The match is won on points, ties going to the better last round
+            standings.sort_by_key(|p| Reverse(p.score));
+            winner_id = standings.first().filter(|p| p.score > 0).map(|p| p.id.clone());
+        }
+
+        let team_standings = self.game.team_standings(&standings);
+        let winning_team = team_standings.first().filter(|t| t.score > 0.0).map(|t| t.team);
+        let rating_changes = self.state.ratings.record_race(&standings).await;
+        self.broadcast(
+            &ServerMessage::GameFinished {
+                winner_id,
+                final_standings: standings,
+                rating_changes,
+                winning_team,
+                team_standings,
+            },
+            None,
+        );
+        info!("Game {} finished", self.game.id);
+
+        // Keep the room open for a while in case the players want a rematch
+        self.rematch_votes.clear();
+        self.schedule(
+            self.state.config.rematch_timeout,
+            Timer::RematchTimeout {
+                started_at: self.game.started_at,
+            },
+        );
+    }
+
+    /// Move a match on to its next round on a fresh text.
+    fn start_next_round(&mut self) {
+        let texts = self.state.texts.clone();
+        let Some(text) = texts.pick(self.game.difficulty, &self.game.language, Some(&self.game.text_id)) else {
+            self.close_game("No text available");
+            return;
+        };
+        if self.game.next_round(text).is_err() {
+            return;
         }
+        self.spectators_revealed = 0;
+        self.rules = self.game.settings.mode.rules();
+
+        self.broadcast(
+            &ServerMessage::RoundStarted {
+                round: self.game.round,
+                game: self.game.clone(),
+            },
+            None,
+        );
+        info!("Game {} is starting round {}", self.game.id, self.game.round);
+        let _ = self.start_countdown();
+    }
+
+    fn handle_request_rematch(&mut self, player_id: &str) -> Result<(), GameError> {
+        self.game.status.check(PlayerAction::RequestRematch)?;
+        if !self.game.players.iter().any(|p| p.id == player_id) {
+            return Err(GameError::PlayerNotFound);
+        }
+
+        self.rematch_votes.insert(player_id.to_string());
+        self.broadcast(
+            &ServerMessage::RematchVote {
+                player_id: player_id.to_string(),
+                votes: self.rematch_votes.len(),
+                needed: self.rematch_votes_needed(),
+            },
+            None,
+        );
+        self.try_rematch();
+        Ok(())
+    }
+
+    /// A strict majority of the players still connected.
+    fn rematch_votes_needed(&self) -> usize {
+        self.game.players.iter().filter(|p| p.connected && !p.bot).count() / 2 + 1
+    }
+
+    /// Reset the game for another race on a fresh text once enough players
+    /// have voted.
+    fn try_rematch(&mut self) {
+        if self.rematch_votes.len() < self.rematch_votes_needed() {
+            return;
+        }
+        let texts = self.state.texts.clone();
+        let Some(text) = texts.pick(self.game.difficulty, &self.game.language, Some(&self.game.text_id)) else {
+            return;
+        };
+        if self.game.rematch(text).is_err() {
+            return;
+        }
+        self.rematch_votes.clear();
+        self.spectators_revealed = 0;
+        self.rules = self.game.settings.mode.rules();
+
+        self.broadcast(&ServerMessage::RematchStarted { game: self.game.clone() }, None);
+        info!("Game {} is back in the lobby for a rematch", self.game.id);
+
+        self.lobby += 1;
+        self.schedule(self.state.config.lobby_timeout, Timer::LobbyTimeout { lobby: self.lobby });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchmaking::Matchmaker;
+    use crate::models::GameSettings;
+    use crate::ratings::RatingStore;
+    use crate::testing;
+    use crate::texts::TextCorpus;
+
+    /// An actor for a game with `players` seated, outside any running task.
+    fn actor(players: usize) -> GameActor {
+        let config = testing::config();
+        let texts = TextCorpus::load(&config);
+        let ratings = RatingStore::load(&config);
+        let state = AppState::new(config, None, texts, ratings, Matchmaker::new().0);
+
+        let game = testing::game("the quick brown fox", GameSettings::default(), players);
+        GameActor::new(state, game, Arc::default())
+    }
+
+    #[tokio::test]
+    async fn rematch_starts_when_a_non_voter_leaves() {
+        let mut actor = actor(4);
+        actor.game.status = GameStatus::Finished;
+        actor.handle_request_rematch("player-1").unwrap();
+        actor.handle_request_rematch("player-2").unwrap();
+        assert_eq!(actor.game.status, GameStatus::Finished);
+
+        // Two of the three players left are a majority
+        actor.handle_player_leave("player-4").await;
+        assert_eq!(actor.game.status, GameStatus::Waiting);
+    }
+
+    #[tokio::test]
+    async fn host_role_passes_on_when_the_host_drops_from_the_lobby() {
+        let mut actor = actor(3);
+        let mut sockets = Vec::new();
+        for n in 1..=3 {
+            let (tx, rx) = mpsc::unbounded_channel();
+            actor.connections.insert(format!("player-{}", n), tx.clone());
+            sockets.push((tx, rx));
+        }
+
+        actor.handle_player_disconnect("player-1", &sockets[0].0).await;
+        assert_eq!(actor.game.host_id, "user-2");
+        assert!(actor.handle_start_game("player-2").is_ok());
+    }
+
+    #[tokio::test]
+    async fn leaving_mid_race_is_rated_as_not_finishing() {
+        let mut actor = actor(3);
+        actor.game.status = GameStatus::Playing;
+        actor.game.started_at = Some(0);
+        actor.handle_keystrokes("player-3", "the quick", 1_000, 1_000).await.unwrap();
+        actor.handle_player_leave("player-3").await;
+
+        for (player_id, at) in [("player-1", 6_000), ("player-2", 7_000)] {
+            actor.handle_keystrokes(player_id, "the quick brown fox", at, at).await.unwrap();
+        }
+        assert_eq!(actor.game.status, GameStatus::Finished);
+
+        let ratings = &actor.state.ratings;
+        assert!(ratings.get("user-1").unwrap().rating > 1000.0);
+        let quitter = ratings.get("user-3").unwrap();
+        assert_eq!(quitter.races, 1);
+        assert!(quitter.rating < ratings.get("user-2").unwrap().rating);
     }
-    
-    Ok(())
 }
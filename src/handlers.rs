@@ -1,10 +1,13 @@
 use crate::auth;
-use crate::game;
-use crate::models::{ClientMessage, Player, ServerMessage};
-use crate::state::{AppState, PlayerConnection};
+use crate::game::{self, GameCommand, GameHandle};
+use crate::matchmaking::Placement;
+use crate::ratings::INITIAL_RATING;
+use crate::models::{ClientMessage, GameError, GameState, Player, ServerMessage};
+use crate::state::{AppState, LatencyTracker};
 use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_tungstenite::{accept_async, tungstenite::Message};
 use tracing::{error, info, warn};
 use uuid::Uuid;
@@ -13,134 +16,400 @@ pub async fn handle_connection(stream: TcpStream, state: AppState) -> Result<(),
     let ws_stream = accept_async(stream).await?;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
 
     let mut player_id: Option<String> = None;
-    let mut game_id: Option<String> = None;
+    let mut game: Option<GameHandle> = None;
     let mut authenticated = false;
-    
-    // Clone state for cleanup
-    let cleanup_state = state.clone();
+    let mut latency = LatencyTracker::default();
+    // Set while waiting in the matchmaking queue, with the queued user's id
+    let mut queued: Option<oneshot::Receiver<Placement>> = None;
+    let mut queued_user: Option<String> = None;
+    let max_compensation = state.config.max_latency_compensation.as_millis() as i64;
 
     // Spawn task to handle outgoing messages
-    let send_task = tokio::spawn(async move {
+    let mut send_task = tokio::spawn(async move {
         while let Some(message) = rx.recv().await {
-            if ws_sender.send(Message::Text(message)).await.is_err() {
+            if ws_sender.send(message).await.is_err() {
                 break;
             }
         }
     });
 
-    // Handle incoming messages
-    while let Some(message) = ws_receiver.next().await {
+    // Handle incoming messages, and the seat matchmaking finds while queued
+    loop {
+        let message = tokio::select! {
+            message = ws_receiver.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            placement = placed(&mut queued) => {
+                queued = None;
+                queued_user = None;
+                match placement {
+                    Ok(placement) => {
+                        player_id = Some(placement.player_id);
+                        game = Some(placement.game);
+                        authenticated = true;
+                    }
+                    // Queued again from another connection
+                    Err(_) => {
+                        let msg = serde_json::to_string(&ServerMessage::QueueLeft).unwrap();
+                        let _ = tx.send(Message::Text(msg));
+                    }
+                }
+                continue;
+            }
+        };
+
         match message {
             Ok(Message::Text(text)) => {
+                let received_at = chrono::Utc::now().timestamp_millis();
                 match serde_json::from_str::<ClientMessage>(&text) {
                     Ok(client_msg) => {
                         match client_msg {
-                            ClientMessage::Auth { token, game_id: gid, game_code, difficulty, text, host_id } => {
+                            ClientMessage::Auth {
+                                token,
+                                ticket,
+                                game_id: gid,
+                                game_code,
+                                difficulty,
+                                host_id,
+                                spectate,
+                            } => {
+                                if authenticated {
+                                    let error = ServerMessage::from(GameError::AlreadyInGame);
+                                    let msg = serde_json::to_string(&error).unwrap();
+                                    let _ = tx.send(Message::Text(msg));
+                                    continue;
+                                }
                                 match auth::verify_token(&token) {
                                     Ok(claims) => {
-                                        let pid = Uuid::new_v4().to_string();
-                                        player_id = Some(pid.clone());
-                                        game_id = Some(gid.clone());
-                                        authenticated = true;
-
-                                        // Create game if it doesn't exist
-                                        if state.get_game(&gid).is_none() {
-                                            let new_game = crate::models::GameState::new(
-                                                gid.clone(),
-                                                game_code,
-                                                difficulty,
-                                                text,
-                                                host_id,
-                                            );
-                                            state.add_game(new_game);
-                                            info!("Created game {} in state", gid);
-                                        }
+                                        let ticket = match auth::verify_ticket(&ticket) {
+                                            Ok(ticket) => ticket,
+                                            Err(e) => {
+                                                warn!("Rejected ticket from user {} for game {}: {}", claims.user_id, gid, e);
+                                                let error = ServerMessage::from(GameError::InvalidTicket(e.to_string()));
+                                                let msg = serde_json::to_string(&error).unwrap();
+                                                let _ = tx.send(Message::Text(msg));
+                                                break;
+                                            }
+                                        };
+
+                                        // Create game if it doesn't exist, but only from parameters the ticket vouches for
+                                        let handle = match state.get_game(&gid) {
+                                            Some(handle) => handle,
+                                            None => {
+                                                let Some(text) = state.texts.pick(difficulty, &ticket.language, None) else {
+                                                    let error = ServerMessage::from(GameError::NoTextAvailable);
+                                                    let msg = serde_json::to_string(&error).unwrap();
+                                                    let _ = tx.send(Message::Text(msg));
+                                                    break;
+                                                };
+                                                let new_game = GameState::new(
+                                                    gid.clone(),
+                                                    game_code,
+                                                    text,
+                                                    host_id,
+                                                    ticket.settings.clone(),
+                                                );
+                                                if let Err(e) = ticket.check(&claims.user_id, &new_game) {
+                                                    warn!("Rejected ticket from user {} for game {}: {}", claims.user_id, gid, e);
+                                                    let error = ServerMessage::from(GameError::InvalidTicket(e));
+                                                    let msg = serde_json::to_string(&error).unwrap();
+                                                    let _ = tx.send(Message::Text(msg));
+                                                    break;
+                                                }
+                                                game::create_game(&state, new_game)
+                                            }
+                                        };
 
+                                        let pid = Uuid::new_v4().to_string();
                                         let player = Player::new(
                                             pid.clone(),
                                             claims.user_id.clone(),
                                             claims.username.clone(),
                                         );
 
-                                        // Add player connection
-                                        let connection = PlayerConnection {
-                                            player_id: pid.clone(),
-                                            user_id: claims.user_id,
-                                            game_id: gid.clone(),
-                                            tx: tx.clone(),
-                                        };
-                                        state.add_player_connection(pid.clone(), connection);
+                                        // Join game; the game sends the player their state
+                                        let joined = handle
+                                            .request(|reply| GameCommand::Join {
+                                                player: Box::new(player),
+                                                ticket: Some(Box::new(ticket)),
+                                                spectate,
+                                                tx: tx.clone(),
+                                                reply,
+                                            })
+                                            .await;
+                                        match joined {
+                                            Ok(()) => {
+                                                player_id = Some(pid);
+                                                game = Some(handle);
+                                                authenticated = true;
 
-                                        // Join game
-                                        match game::handle_player_join(&state, &gid, player).await {
-                                            Ok(response) => {
-                                                let msg = serde_json::to_string(&response).unwrap();
-                                                let _ = tx.send(msg);
+                                                // Joining a game directly replaces any place in the queue
+                                                if let Some(user_id) = queued_user.take() {
+                                                    state.matchmaker.cancel(&user_id);
+                                                    queued = None;
+                                                }
                                             }
                                             Err(e) => {
-                                                let error = ServerMessage::Error {
-                                                    message: e,
-                                                };
+                                                warn!("User {} could not join game {}: {}", claims.user_id, gid, e);
+                                                let error = ServerMessage::from(e);
                                                 let msg = serde_json::to_string(&error).unwrap();
-                                                let _ = tx.send(msg);
+                                                let _ = tx.send(Message::Text(msg));
+                                                break;
                                             }
                                         }
                                     }
                                     Err(e) => {
                                         error!("Auth failed: {}", e);
-                                        let error = ServerMessage::Error {
-                                            message: "Authentication failed".to_string(),
-                                        };
+                                        let error = ServerMessage::from(GameError::AuthenticationFailed);
                                         let msg = serde_json::to_string(&error).unwrap();
-                                        let _ = tx.send(msg);
+                                        let _ = tx.send(Message::Text(msg));
                                         break;
                                     }
                                 }
                             }
+                            ClientMessage::Resume { token, resume_token } => {
+                                if authenticated {
+                                    let error = ServerMessage::from(GameError::AlreadyInGame);
+                                    let msg = serde_json::to_string(&error).unwrap();
+                                    let _ = tx.send(Message::Text(msg));
+                                    continue;
+                                }
+                                let session = auth::verify_token(&token)
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|claims| {
+                                        state
+                                            .get_session(&resume_token)
+                                            .filter(|session| session.user_id == claims.user_id)
+                                            .ok_or_else(|| "Unknown resume token".to_string())
+                                    });
+
+                                match session {
+                                    Ok(session) => {
+                                        let resumed = match state.get_game(&session.game_id) {
+                                            Some(handle) => handle
+                                                .request(|reply| GameCommand::Resume {
+                                                    player_id: session.player_id.clone(),
+                                                    resume_token,
+                                                    tx: tx.clone(),
+                                                    reply,
+                                                })
+                                                .await
+                                                .map(|()| handle),
+                                            None => Err(GameError::GameNotFound),
+                                        };
+
+                                        match resumed {
+                                            Ok(handle) => {
+                                                player_id = Some(session.player_id);
+                                                game = Some(handle);
+                                                authenticated = true;
+                                            }
+                                            Err(e) => {
+                                                let error = ServerMessage::from(e);
+                                                let msg = serde_json::to_string(&error).unwrap();
+                                                let _ = tx.send(Message::Text(msg));
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!("Resume failed: {}", e);
+                                        let error = ServerMessage::from(GameError::ResumeFailed);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::QueuePlay { token, difficulty, language } => {
+                                if authenticated {
+                                    let error = ServerMessage::from(GameError::AlreadyInGame);
+                                    let msg = serde_json::to_string(&error).unwrap();
+                                    let _ = tx.send(Message::Text(msg));
+                                    continue;
+                                }
+                                if !state.texts.has(difficulty, &language) {
+                                    let error = ServerMessage::from(GameError::NoTextAvailable);
+                                    let msg = serde_json::to_string(&error).unwrap();
+                                    let _ = tx.send(Message::Text(msg));
+                                    continue;
+                                }
+
+                                match auth::verify_token(&token) {
+                                    Ok(claims) => {
+                                        // Laravel's rating only counts until the user has raced here
+                                        let rating = state
+                                            .ratings
+                                            .get(&claims.user_id)
+                                            .map(|r| r.rating)
+                                            .or(claims.rating.map(f64::from))
+                                            .unwrap_or(INITIAL_RATING);
+                                        queued = Some(state.matchmaker.enqueue(
+                                            &claims,
+                                            rating.round() as u32,
+                                            difficulty,
+                                            language.clone(),
+                                            tx.clone(),
+                                        ));
+                                        queued_user = Some(claims.user_id);
+                                        let msg = serde_json::to_string(&ServerMessage::Queued { difficulty, language }).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                    Err(e) => {
+                                        warn!("Queue auth failed: {}", e);
+                                        let error = ServerMessage::from(GameError::AuthenticationFailed);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::LeaveQueue => {
+                                if let Some(user_id) = queued_user.take() {
+                                    state.matchmaker.cancel(&user_id);
+                                    queued = None;
+                                    let msg = serde_json::to_string(&ServerMessage::QueueLeft).unwrap();
+                                    let _ = tx.send(Message::Text(msg));
+                                }
+                            }
                             _ if !authenticated => {
-                                let error = ServerMessage::Error {
-                                    message: "Not authenticated".to_string(),
-                                };
+                                let error = ServerMessage::from(GameError::NotAuthenticated);
                                 let msg = serde_json::to_string(&error).unwrap();
-                                let _ = tx.send(msg);
+                                let _ = tx.send(Message::Text(msg));
+                            }
+                            ClientMessage::Leave => {
+                                if let (Some(pid), Some(handle)) = (player_id.take(), game.take()) {
+                                    handle.send(GameCommand::Leave { player_id: pid });
+                                }
+                                authenticated = false;
                             }
                             ClientMessage::StartGame => {
-                                if let (Some(ref pid), Some(ref gid)) = (&player_id, &game_id) {
-                                    if let Err(e) = game::handle_start_game(&state, gid, pid).await {
-                                        let error = ServerMessage::Error { message: e };
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::StartGame {
+                                        player_id: pid.clone(),
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
                                         let msg = serde_json::to_string(&error).unwrap();
-                                        let _ = tx.send(msg);
+                                        let _ = tx.send(Message::Text(msg));
                                     }
                                 }
                             }
-                            ClientMessage::UpdateProgress {
-                                progress,
-                                wpm,
-                                accuracy,
-                            } => {
-                                if let (Some(ref pid), Some(ref gid)) = (&player_id, &game_id) {
-                                    let _ = game::handle_update_progress(
-                                        &state, gid, pid, progress, wpm, accuracy,
-                                    )
-                                    .await;
+                            ClientMessage::KickPlayer { player_id: target_id } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::Kick {
+                                        player_id: pid.clone(),
+                                        target_id,
+                                        ban: false,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
                                 }
                             }
-                            ClientMessage::FinishGame {
-                                wpm,
-                                accuracy,
-                                time_taken: _,
-                            } => {
-                                if let (Some(ref pid), Some(ref gid)) = (&player_id, &game_id) {
-                                    let _ = game::handle_finish_game(&state, gid, pid, wpm, accuracy).await;
+                            ClientMessage::BanPlayer { player_id: target_id } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::Kick {
+                                        player_id: pid.clone(),
+                                        target_id,
+                                        ban: true,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
                                 }
                             }
+                            ClientMessage::TransferHost { player_id: new_host_id } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::TransferHost {
+                                        player_id: pid.clone(),
+                                        new_host_id,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::AssignTeam { player_id: target_id, team } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::AssignTeam {
+                                        player_id: pid.clone(),
+                                        target_id,
+                                        team,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::AddBot { wpm } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::AddBot {
+                                        player_id: pid.clone(),
+                                        wpm,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::Keystrokes { keys } => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    // Time the keystrokes were typed, estimated from the measured latency
+                                    let typed_at = received_at - latency.compensation(max_compensation);
+                                    let sent = handle.send(GameCommand::Keystrokes {
+                                        player_id: pid.clone(),
+                                        keys,
+                                        received_at,
+                                        typed_at,
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::RequestRematch => {
+                                if let (Some(ref pid), Some(ref handle)) = (&player_id, &game) {
+                                    let sent = handle.send(GameCommand::RequestRematch {
+                                        player_id: pid.clone(),
+                                    });
+                                    if !sent {
+                                        let error = ServerMessage::from(GameError::GameNotFound);
+                                        let msg = serde_json::to_string(&error).unwrap();
+                                        let _ = tx.send(Message::Text(msg));
+                                    }
+                                }
+                            }
+                            ClientMessage::TimeSync { client_sent_at } => {
+                                let server_sent_at = chrono::Utc::now().timestamp_millis();
+                                latency.sync_sent(server_sent_at);
+                                let reply = ServerMessage::TimeSync {
+                                    client_sent_at,
+                                    server_received_at: received_at,
+                                    server_sent_at,
+                                };
+                                let _ = tx.send(Message::Text(serde_json::to_string(&reply).unwrap()));
+                            }
+                            ClientMessage::TimeSyncAck { server_sent_at } => {
+                                latency.sync_acked(server_sent_at, received_at);
+                            }
                             ClientMessage::Ping => {
                                 let pong = ServerMessage::Pong;
                                 let msg = serde_json::to_string(&pong).unwrap();
-                                let _ = tx.send(msg);
+                                let _ = tx.send(Message::Text(msg));
                             }
                         }
                     }
@@ -162,14 +431,30 @@ pub async fn handle_connection(stream: TcpStream, state: AppState) -> Result<(),
         }
     }
 
-    // Cleanup on disconnect - always clean up if authenticated
+    // Cleanup on disconnect - the player keeps their seat for the grace period
     info!("Connection closed, cleaning up...");
-    if let (Some(pid), Some(gid)) = (player_id.clone(), game_id.clone()) {
-        info!("Removing player {} from game {}", pid, gid);
-        cleanup_state.remove_player_connection(&pid);
-        game::handle_player_leave(&cleanup_state, &gid, &pid).await;
+    if let Some(user_id) = queued_user {
+        state.matchmaker.cancel(&user_id);
+    }
+    if let (Some(pid), Some(handle)) = (player_id, game) {
+        handle.send(GameCommand::Disconnect {
+            player_id: pid,
+            tx: tx.clone(),
+        });
     }
 
-    send_task.abort();
+    // Let queued messages (e.g. a rejection reason) reach the client before closing
+    drop(tx);
+    if tokio::time::timeout(Duration::from_secs(1), &mut send_task).await.is_err() {
+        send_task.abort();
+    }
     Ok(())
 }
+
+/// Wait for matchmaking to seat this connection, or forever if it is not queued.
+async fn placed(queued: &mut Option<oneshot::Receiver<Placement>>) -> Result<Placement, oneshot::error::RecvError> {
+    match queued {
+        Some(placement) => placement.await,
+        None => std::future::pending().await,
+    }
+}
@@ -127,6 +127,41 @@ async fn handle_request(
                 .body(Full::new(Bytes::from(prometheus_metrics)))
                 .unwrap())
         }
+        "/ratings" => {
+            // Leaderboard, `?limit=N` (default 50, at most 500)
+            let limit = req
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("limit=")))
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .unwrap_or(50)
+                .min(500);
+            let json = serde_json::to_string(&state.ratings.leaderboard(limit)).unwrap();
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Full::new(Bytes::from(json)))
+                .unwrap())
+        }
+        _ if path.starts_with("/ratings/") => {
+            let user_id = &path["/ratings/".len()..];
+            match state.ratings.get(user_id) {
+                Some(rating) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Full::new(Bytes::from(serde_json::to_string(&rating).unwrap())))
+                    .unwrap()),
+                None => Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header("Content-Type", "application/json")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Full::new(Bytes::from(r#"{"error":"Unrated user"}"#.to_string())))
+                    .unwrap()),
+            }
+        }
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .header("Content-Type", "application/json")
@@ -1,17 +1,27 @@
 mod auth;
+mod bots;
+mod config;
+mod difficulty;
 mod game;
 mod handlers;
 mod http_server;
+mod matchmaking;
 mod metrics;
 mod models;
+mod modes;
+mod ratings;
+mod results;
 mod state;
+mod texts;
+#[cfg(test)]
+mod testing;
+mod typing;
 
 use dotenv::dotenv;
 use std::env;
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tracing::{error, info};
-use tracing_subscriber;
 
 #[tokio::main]
 async fn main() {
@@ -42,7 +52,13 @@ async fn main() {
     let http_socket_addr: SocketAddr = http_addr.parse().expect("Unable to parse HTTP socket address");
 
     // Initialize global state
-    let state = state::AppState::new();
+    let config = config::Config::from_env();
+    let reporter = results::ResultReporter::start(&config);
+    let (matchmaker, match_queue) = matchmaking::Matchmaker::new();
+    let texts = texts::TextCorpus::load(&config);
+    let ratings = ratings::RatingStore::load(&config);
+    let state = state::AppState::new(config, reporter, texts, ratings, matchmaker);
+    tokio::spawn(match_queue.run(state.clone()));
 
     info!("🎮 QCXIS Game Server starting...");
     info!("📡 WebSocket listening on: ws://{}", ws_socket_addr);
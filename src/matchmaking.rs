@@ -0,0 +1,268 @@
+use crate::auth::Claims;
+use crate::config::Config;
+use crate::difficulty::Difficulty;
+use crate::game::{self, GameCommand, GameHandle};
+use crate::models::{GameError, GameSettings, GameState, Player, ServerMessage};
+use crate::state::{AppState, Tx};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How often the queue looks for matches.
+const MATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where matchmaking seated a queued connection.
+pub struct Placement {
+    pub player_id: String,
+    pub game: GameHandle,
+}
+
+struct QueueEntry {
+    user_id: String,
+    username: String,
+    difficulty: Difficulty,
+    language: String,
+    rating: u32,
+    queued_at: Instant,
+    tx: Tx,
+    placed: oneshot::Sender<Placement>,
+}
+
+enum Command {
+    Enqueue(QueueEntry),
+    Cancel { user_id: String },
+}
+
+/// Cheap, cloneable handle to the matchmaking queue.
+#[derive(Clone)]
+pub struct Matchmaker {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Matchmaker {
+    /// Create the handle and the queue it feeds. The queue starts once
+    /// `MatchQueue::run` is spawned with the app state.
+    pub fn new() -> (Self, MatchQueue) {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let queue = MatchQueue {
+            rx,
+            entries: Vec::new(),
+        };
+        (Self { commands }, queue)
+    }
+
+    /// Queue a user for a race at `difficulty` in `language`. The receiver resolves
+    /// once they have been seated in a game; it is dropped if they are queued again elsewhere.
+    pub fn enqueue(
+        &self,
+        claims: &Claims,
+        rating: u32,
+        difficulty: Difficulty,
+        language: String,
+        tx: Tx,
+    ) -> oneshot::Receiver<Placement> {
+        let (placed, placement) = oneshot::channel();
+        let _ = self.commands.send(Command::Enqueue(QueueEntry {
+            user_id: claims.user_id.clone(),
+            username: claims.username.clone(),
+            difficulty,
+            language,
+            rating,
+            queued_at: Instant::now(),
+            tx,
+            placed,
+        }));
+        placement
+    }
+
+    pub fn cancel(&self, user_id: &str) {
+        let _ = self.commands.send(Command::Cancel {
+            user_id: user_id.to_string(),
+        });
+    }
+}
+
+/// The task that groups queued users into games.
+pub struct MatchQueue {
+    rx: mpsc::UnboundedReceiver<Command>,
+    entries: Vec<QueueEntry>,
+}
+
+impl MatchQueue {
+    pub async fn run(mut self, state: AppState) {
+        let mut interval = tokio::time::interval(MATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                command = self.rx.recv() => match command {
+                    Some(Command::Enqueue(entry)) => {
+                        // A user waits in one queue at a time; the newest request wins
+                        self.entries.retain(|e| e.user_id != entry.user_id);
+                        info!("User {} queued for a {} race", entry.user_id, entry.difficulty);
+                        self.entries.push(entry);
+                    }
+                    Some(Command::Cancel { user_id }) => self.entries.retain(|e| e.user_id != user_id),
+                    None => break,
+                },
+                _ = interval.tick() => self.form_matches(&state),
+            }
+        }
+    }
+
+    fn form_matches(&mut self, state: &AppState) {
+        // Forget connections that went away while queued
+        self.entries.retain(|e| !e.placed.is_closed());
+        let config = &state.config;
+        let now = Instant::now();
+
+        // Entries are oldest first, so whoever has waited longest anchors a match first
+        let mut anchor = 0;
+        while anchor < self.entries.len() {
+            let mut group = group_around(&self.entries, anchor, now, config);
+
+            let waited = now - self.entries[anchor].queued_at;
+            let ready = group.len() >= config.match_max_players
+                || (group.len() >= config.match_min_players && waited >= config.match_fill_wait);
+            if !ready {
+                anchor += 1;
+                continue;
+            }
+
+            // Remove from the back so the remaining indices stay valid
+            group.sort_unstable_by(|a, b| b.cmp(a));
+            let mut players: Vec<QueueEntry> = group.into_iter().map(|i| self.entries.remove(i)).collect();
+            players.reverse();
+            tokio::spawn(start_match(state.clone(), players));
+        }
+    }
+}
+
+/// Indices of the entries that can race with `anchor`, anchor included. Each
+/// one must be compatible with everyone already picked, not just the anchor.
+fn group_around(entries: &[QueueEntry], anchor: usize, now: Instant, config: &Config) -> Vec<usize> {
+    let mut group = vec![anchor];
+    for other in 0..entries.len() {
+        if group.len() >= config.match_max_players {
+            break;
+        }
+        if other != anchor && group.iter().all(|&member| compatible(&entries[member], &entries[other], now, config)) {
+            group.push(other);
+        }
+    }
+    group
+}
+
+/// Users match when they want the same difficulty and language and their ratings are
+/// within the wider of their two windows. Windows grow the longer someone waits.
+fn compatible(a: &QueueEntry, b: &QueueEntry, now: Instant, config: &Config) -> bool {
+    let window = |entry: &QueueEntry| {
+        let waited = (now - entry.queued_at).as_secs() as u32;
+        config.match_rating_window + config.match_window_growth * waited
+    };
+    a.difficulty == b.difficulty && a.language == b.language && a.rating.abs_diff(b.rating) <= window(a).max(window(b))
+}
+
+/// Create a game for a group of queued users, seat each of them in it and start it.
+async fn start_match(state: AppState, entries: Vec<QueueEntry>) {
+    let difficulty = entries[0].difficulty;
+    // Users are only queued for texts the corpus has, so this only fails if it is empty
+    let Some(text) = state.texts.pick(difficulty, &entries[0].language, None) else {
+        warn!("No {} {} text for a match", entries[0].language, difficulty);
+        let error = ServerMessage::from(GameError::NoTextAvailable);
+        for entry in entries {
+            let _ = entry.tx.send(Message::Text(serde_json::to_string(&error).unwrap()));
+        }
+        return;
+    };
+
+    let game_id = Uuid::new_v4().to_string();
+    let code = Uuid::new_v4().simple().to_string()[..6].to_uppercase();
+    let settings = GameSettings {
+        max_players: entries.len(),
+        private: true,
+        allowed_user_ids: entries.iter().map(|e| e.user_id.clone()).collect(),
+        ..GameSettings::default()
+    };
+    // The longest-waiting player hosts, unless they are gone by the time seats are handed out
+    let host_id = entries[0].user_id.clone();
+    let handle = game::create_game(
+        &state,
+        GameState::new(game_id.clone(), code.clone(), text, host_id, settings),
+    );
+    info!("Matched {} players into game {}", entries.len(), game_id);
+
+    for entry in entries {
+        if entry.placed.is_closed() {
+            continue;
+        }
+        let found = ServerMessage::MatchFound {
+            game_id: game_id.clone(),
+            game_code: code.clone(),
+            difficulty,
+        };
+        let _ = entry.tx.send(Message::Text(serde_json::to_string(&found).unwrap()));
+
+        let player_id = Uuid::new_v4().to_string();
+        let player = Player::new(player_id.clone(), entry.user_id.clone(), entry.username.clone());
+        let joined = handle
+            .request(|reply| GameCommand::Join {
+                player: Box::new(player),
+                ticket: None,
+                spectate: false,
+                tx: entry.tx.clone(),
+                reply,
+            })
+            .await;
+
+        match joined {
+            Ok(()) => {
+                let placement = Placement {
+                    player_id: player_id.clone(),
+                    game: handle.clone(),
+                };
+                // The connection closed in the meantime; give the seat back
+                if entry.placed.send(placement).is_err() {
+                    handle.send(GameCommand::Leave { player_id });
+                }
+            }
+            Err(e) => {
+                let error = ServerMessage::from(e);
+                let _ = entry.tx.send(Message::Text(serde_json::to_string(&error).unwrap()));
+            }
+        }
+    }
+    handle.send(GameCommand::MatchSeated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn entry(rating: u32, queued_at: Instant) -> QueueEntry {
+        let (tx, _) = mpsc::unbounded_channel();
+        let (placed, _) = oneshot::channel();
+        QueueEntry {
+            user_id: format!("user-{}", rating),
+            username: format!("User {}", rating),
+            difficulty: Difficulty::Medium,
+            language: "en".to_string(),
+            rating,
+            queued_at,
+            tx,
+            placed,
+        }
+    }
+
+    #[test]
+    fn members_are_all_within_each_others_window() {
+        let config = testing::config();
+        let now = Instant::now();
+        let entries = vec![entry(1000, now), entry(900, now), entry(1100, now)];
+
+        // Both are within 100 of the anchor, but 200 apart from each other
+        assert_eq!(group_around(&entries, 0, now, &config), vec![0, 1]);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::state::AppState;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
 use sysinfo::{CpuRefreshKind, MemoryRefreshKind, RefreshKind, System};
 
@@ -89,10 +90,11 @@ impl MetricsCollector {
 
         // Game metrics
         let total_games = state.games.len();
-        let active_connections = state.player_connections.len();
+        let mut active_connections = 0;
         let mut total_players = 0;
-        for entry in state.game_players.iter() {
-            total_players += entry.value().len();
+        for entry in state.games.iter() {
+            active_connections += entry.stats.connections.load(Ordering::Relaxed);
+            total_players += entry.stats.players.load(Ordering::Relaxed);
         }
 
         ServerMetrics {
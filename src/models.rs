@@ -1,4 +1,10 @@
+use crate::difficulty::Difficulty;
+use crate::ratings::RatingChange;
+use crate::texts::{default_language, Text};
+use crate::typing::TypingSession;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
@@ -8,28 +14,385 @@ pub struct Player {
     pub wpm: u32,
     pub accuracy: f32,
     pub progress: u32,
+    pub errors: u32,
     pub finished: bool,
     pub finished_at: Option<i64>,
+    /// Did not finish before the race time limit
+    pub dnf: bool,
+    /// When the race mode knocked this player out
+    pub eliminated_at: Option<i64>,
+    /// Placement points from this race, or from every round of a match
+    pub score: u32,
+    /// Team number, from 1 to `settings.teams`, in team games
+    pub team: Option<u32>,
+    /// Played by the server rather than a user
+    pub bot: bool,
+    /// Left the game while the race or match was under way
+    pub left: bool,
+    pub connected: bool,
+    #[serde(skip)]
+    pub disconnected_at: Option<i64>,
+    #[serde(skip)]
+    pub typing: TypingSession,
+    /// Characters of the text sent to this player so far
+    #[serde(skip)]
+    pub revealed: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameState {
     pub id: String,
     pub code: String,
-    pub difficulty: String,
+    pub difficulty: Difficulty,
+    pub language: String,
+    /// Corpus id of the race text. It, the text and its source are withheld
+    /// from game state; players get them through `TextRevealed` once the race starts.
+    #[serde(skip)]
+    pub text_id: String,
+    #[serde(skip)]
     pub text: String,
+    /// Length of the text in characters
+    pub text_length: usize,
+    /// Attribution to show alongside the text
+    #[serde(skip)]
+    pub text_source: Option<String>,
+    /// User id of the current host
     pub host_id: String,
+    /// Host named by the game ticket. Tickets keep naming them after the
+    /// host role has moved on, so joins are checked against this instead.
+    #[serde(skip)]
+    pub ticket_host_id: String,
+    pub settings: GameSettings,
     pub players: Vec<Player>,
+    /// Connections watching the race without taking part
+    pub spectators: usize,
     pub status: GameStatus,
+    /// Round of the match being played, from 1 to `settings.rounds`
+    pub round: u32,
+    /// Scheduled race start in milliseconds, set when the countdown begins
     pub started_at: Option<i64>,
+    /// User ids the host has banned from this game
+    #[serde(skip)]
+    pub banned_user_ids: HashSet<String>,
+    /// Players who left during the race or match. They are still ranked, and
+    /// rated, with everyone else when it ends.
+    #[serde(skip)]
+    pub departed: Vec<Player>,
 }
 
+/// Who may join a game, as bound by the game ticket.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct GameSettings {
+    pub max_players: usize,
+    /// Private rooms only admit ticket holders and, in games the server
+    /// seats itself, the allowlisted users
+    pub private: bool,
+    /// Never read from a ticket: Laravel decides who is invited when it
+    /// issues tickets, so the ticket is the invitation
+    #[serde(skip)]
+    pub allowed_user_ids: Vec<String>,
+    /// Whether players may still join once the race is `Playing`
+    pub allow_late_join: bool,
+    /// Start the countdown as soon as `max_players` have joined
+    pub auto_start: bool,
+    /// How much of the text players see while racing
+    pub text_reveal: TextReveal,
+    pub mode: RaceMode,
+    /// Races in the match, each on a different text
+    pub rounds: u32,
+    /// Number of teams players are split into; 0 for everyone on their own
+    pub teams: u32,
+}
+
+/// A team's result, sent with `GameFinished` in team games.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamStanding {
+    pub team: u32,
+    pub player_ids: Vec<String>,
+    /// Average placement points of the members, so team sizes may differ
+    pub score: f64,
+    /// Average WPM of the members
+    pub wpm: u32,
+}
+
+/// Rules deciding when a race is over and how it is ranked.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaceMode {
+    /// First to type the whole text wins
+    #[default]
+    Classic,
+    /// Type for a fixed time; most characters typed correctly wins
+    Timed { seconds: u64 },
+    /// First to type the first `count` words of the text wins
+    Words { count: usize },
+    /// Like `Classic`, but typing on past a mistake eliminates the player
+    SuddenDeath,
+    /// Every `interval_seconds` the player furthest behind is eliminated,
+    /// until one is left
+    BattleRoyale { interval_seconds: u64 },
+    /// The members of a team type consecutive parts of the text in turn
+    Relay,
+}
+
+/// How the race text is handed out once the race has started.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextReveal {
+    /// The whole text at once
+    Full,
+    /// The word being typed and the one after it
+    Word,
+    /// The line being typed and the one after it
+    Line,
+}
+
+impl TextReveal {
+    /// Characters of `text` to show a player whose cursor is at `position`.
+    pub fn visible(&self, text: &[char], position: usize) -> usize {
+        let chunk_end = |from: usize| -> usize {
+            let rest = &text[from.min(text.len())..];
+            let end = match self {
+                TextReveal::Full => return text.len(),
+                // A word runs through the whitespace after it
+                TextReveal::Word => rest
+                    .iter()
+                    .position(|c| c.is_whitespace())
+                    .map(|i| i + rest[i..].iter().take_while(|c| c.is_whitespace()).count()),
+                TextReveal::Line => rest.iter().position(|&c| c == '\n').map(|i| i + 1),
+            };
+            from + end.unwrap_or(rest.len())
+        };
+        chunk_end(chunk_end(position)).min(text.len())
+    }
+}
+
+impl Default for GameSettings {
+    fn default() -> Self {
+        Self {
+            max_players: 10,
+            private: false,
+            allowed_user_ids: Vec::new(),
+            allow_late_join: true,
+            auto_start: false,
+            text_reveal: TextReveal::Full,
+            mode: RaceMode::default(),
+            rounds: 1,
+            teams: 0,
+        }
+    }
+}
+
+/// Lifecycle of a game. Transitions only go through `GameState::transition`:
+///
+/// `Waiting -> Countdown -> Playing -> Finished`, and any unfinished game may
+/// move to `Aborted`. Races with several rounds loop through
+/// `Playing -> Intermission -> Countdown` between rounds. A rematch takes a
+/// finished game back to `Waiting`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum GameStatus {
     Waiting,
+    Countdown,
     Playing,
+    /// Between two rounds of a match
+    Intermission,
     Finished,
+    Aborted,
+}
+
+/// Things a player can ask a game to do, checked against its status.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerAction {
+    Join,
+    StartGame,
+    Keystrokes,
+    TransferHost,
+    Kick,
+    RequestRematch,
+    Spectate,
+    AssignTeam,
+    AddBot,
+}
+
+impl GameStatus {
+    pub fn can_transition_to(self, next: GameStatus) -> bool {
+        use GameStatus::*;
+        matches!(
+            (self, next),
+            (Waiting, Countdown)
+                | (Countdown, Playing)
+                | (Playing, Intermission)
+                | (Intermission, Countdown)
+                | (Playing, Finished)
+                | (Finished, Waiting)
+                | (Waiting | Countdown | Playing | Intermission, Aborted)
+        )
+    }
+
+    pub fn allows(self, action: PlayerAction) -> bool {
+        use GameStatus::*;
+        match action {
+            PlayerAction::Join => matches!(self, Waiting | Countdown | Playing | Intermission),
+            PlayerAction::StartGame => self == Waiting,
+            PlayerAction::Keystrokes => self == Playing,
+            PlayerAction::TransferHost => self != Aborted,
+            PlayerAction::Kick => matches!(self, Waiting | Countdown | Playing | Intermission),
+            PlayerAction::RequestRematch => self == Finished,
+            PlayerAction::Spectate => self != Aborted,
+            PlayerAction::AssignTeam => self == Waiting,
+            PlayerAction::AddBot => self == Waiting,
+        }
+    }
+
+    /// Refuse `action` with a structured error unless this status allows it.
+    pub fn check(self, action: PlayerAction) -> Result<(), GameError> {
+        if self.allows(action) {
+            Ok(())
+        } else {
+            Err(GameError::InvalidState { action, status: self })
+        }
+    }
+}
+
+impl fmt::Display for GameStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            GameStatus::Waiting => "waiting",
+            GameStatus::Countdown => "counting down",
+            GameStatus::Playing => "playing",
+            GameStatus::Intermission => "between rounds",
+            GameStatus::Finished => "finished",
+            GameStatus::Aborted => "aborted",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for PlayerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlayerAction::Join => "join",
+            PlayerAction::StartGame => "start the game",
+            PlayerAction::Keystrokes => "type",
+            PlayerAction::TransferHost => "transfer the host role",
+            PlayerAction::Kick => "remove players",
+            PlayerAction::RequestRematch => "request a rematch",
+            PlayerAction::Spectate => "watch",
+            PlayerAction::AssignTeam => "change teams",
+            PlayerAction::AddBot => "add bots",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a request was refused, sent to the client as `ServerMessage::Error`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameError {
+    AuthenticationFailed,
+    NotAuthenticated,
+    AlreadyInGame,
+    InvalidTicket(String),
+    ResumeFailed,
+    SessionExpired,
+    GameNotFound,
+    PlayerNotFound,
+    NotHost,
+    CannotTargetSelf,
+    InvalidTeam,
+    NotYourTurn,
+    TypingTooFast,
+    Banned,
+    RoomFull,
+    LateJoinDisabled,
+    NotInvited,
+    AlreadyFinished,
+    Eliminated,
+    RaceNotStarted,
+    NoTextAvailable,
+    InvalidState { action: PlayerAction, status: GameStatus },
+    InvalidTransition { from: GameStatus, to: GameStatus },
+}
+
+impl GameError {
+    /// Stable identifier clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            GameError::AuthenticationFailed => "authentication_failed",
+            GameError::NotAuthenticated => "not_authenticated",
+            GameError::AlreadyInGame => "already_in_game",
+            GameError::InvalidTicket(_) => "invalid_ticket",
+            GameError::ResumeFailed => "resume_failed",
+            GameError::SessionExpired => "session_expired",
+            GameError::GameNotFound => "game_not_found",
+            GameError::PlayerNotFound => "player_not_found",
+            GameError::NotHost => "not_host",
+            GameError::CannotTargetSelf => "cannot_target_self",
+            GameError::InvalidTeam => "invalid_team",
+            GameError::NotYourTurn => "not_your_turn",
+            GameError::TypingTooFast => "typing_too_fast",
+            GameError::Banned => "banned",
+            GameError::RoomFull => "room_full",
+            GameError::LateJoinDisabled => "late_join_disabled",
+            GameError::NotInvited => "not_invited",
+            GameError::AlreadyFinished => "already_finished",
+            GameError::Eliminated => "eliminated",
+            GameError::RaceNotStarted => "race_not_started",
+            GameError::NoTextAvailable => "no_text_available",
+            GameError::InvalidState { .. } => "invalid_state",
+            GameError::InvalidTransition { .. } => "invalid_transition",
+        }
+    }
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::AuthenticationFailed => write!(f, "Authentication failed"),
+            GameError::NotAuthenticated => write!(f, "Not authenticated"),
+            GameError::AlreadyInGame => write!(f, "Already in a game"),
+            GameError::InvalidTicket(reason) => write!(f, "Invalid game ticket: {}", reason),
+            GameError::ResumeFailed => write!(f, "Resume failed"),
+            GameError::SessionExpired => write!(f, "Session expired"),
+            GameError::GameNotFound => write!(f, "Game not found"),
+            GameError::PlayerNotFound => write!(f, "Player not found"),
+            GameError::NotHost => write!(f, "Only the host can do that"),
+            GameError::CannotTargetSelf => write!(f, "You cannot do that to yourself"),
+            GameError::InvalidTeam => write!(f, "No such team"),
+            GameError::NotYourTurn => write!(f, "Wait for your teammate to finish their part"),
+            GameError::TypingTooFast => write!(f, "Keystrokes arrived faster than anyone can type"),
+            GameError::Banned => write!(f, "You are banned from this game"),
+            GameError::RoomFull => write!(f, "Game is full"),
+            GameError::LateJoinDisabled => write!(f, "Game does not allow joining after the race has started"),
+            GameError::NotInvited => write!(f, "Game is private"),
+            GameError::AlreadyFinished => write!(f, "Player already finished"),
+            GameError::Eliminated => write!(f, "You have been eliminated"),
+            GameError::RaceNotStarted => write!(f, "Race has not started"),
+            GameError::NoTextAvailable => write!(f, "No texts are available for this difficulty and language"),
+            GameError::InvalidState { action, status } => {
+                write!(f, "Cannot {} while the game is {}", action, status)
+            }
+            GameError::InvalidTransition { from, to } => {
+                write!(f, "Game cannot go from {} to {}", from, to)
+            }
+        }
+    }
+}
+
+impl From<GameError> for ServerMessage {
+    fn from(error: GameError) -> Self {
+        ServerMessage::Error {
+            code: error.code().to_string(),
+            message: error.to_string(),
+            status: match error {
+                GameError::InvalidState { status, .. } => Some(status),
+                GameError::InvalidTransition { from, .. } => Some(from),
+                _ => None,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,22 +400,60 @@ pub enum GameStatus {
 pub enum ClientMessage {
     Auth {
         token: String,
+        ticket: String,
         game_id: String,
         game_code: String,
-        difficulty: String,
-        text: String,
+        difficulty: Difficulty,
         host_id: String,
+        /// Watch the race instead of joining it as a player
+        #[serde(default)]
+        spectate: bool,
+    },
+    Resume {
+        token: String,
+        resume_token: String,
     },
+    /// Ask matchmaking for a game instead of joining a known one
+    QueuePlay {
+        token: String,
+        difficulty: Difficulty,
+        #[serde(default = "default_language")]
+        language: String,
+    },
+    LeaveQueue,
+    Leave,
     StartGame,
-    UpdateProgress {
-        progress: u32,
-        wpm: u32,
-        accuracy: f32,
+    /// Host only: hand the host role to another player
+    TransferHost {
+        player_id: String,
+    },
+    /// Host only: move a player to another team before the race
+    AssignTeam {
+        player_id: String,
+        team: u32,
     },
-    FinishGame {
+    /// Host only: seat a bot typing at around `wpm`
+    AddBot {
         wpm: u32,
-        accuracy: f32,
-        time_taken: u32,
+    },
+    /// Host only: remove a player, who may join again
+    KickPlayer {
+        player_id: String,
+    },
+    /// Host only: remove a player and refuse them for the rest of the game
+    BanPlayer {
+        player_id: String,
+    },
+    Keystrokes {
+        keys: String,
+    },
+    /// Vote to race again once the game has finished
+    RequestRematch,
+    TimeSync {
+        client_sent_at: i64,
+    },
+    TimeSyncAck {
+        server_sent_at: i64,
     },
     Ping,
 }
@@ -62,6 +463,18 @@ pub enum ClientMessage {
 pub enum ServerMessage {
     Connected {
         player_id: String,
+        resume_token: String,
+    },
+    Queued {
+        difficulty: Difficulty,
+        language: String,
+    },
+    QueueLeft,
+    /// Matchmaking created a game; `connected` and `game_state` follow
+    MatchFound {
+        game_id: String,
+        game_code: String,
+        difficulty: Difficulty,
     },
     GameState {
         game: GameState,
@@ -69,17 +482,53 @@ pub enum ServerMessage {
     PlayerJoined {
         player: Player,
     },
+    /// Sent to a spectator in place of `Connected`
+    Spectating {
+        spectator_id: String,
+    },
+    SpectatorsChanged {
+        spectators: usize,
+    },
     PlayerLeft {
         player_id: String,
     },
+    PlayerDisconnected {
+        player_id: String,
+    },
+    PlayerReconnected {
+        player_id: String,
+    },
+    HostChanged {
+        host_id: String,
+        player_id: String,
+    },
+    PlayerKicked {
+        player_id: String,
+        banned: bool,
+    },
+    CountdownStarted {
+        start_at: i64,
+    },
+    CountdownTick {
+        seconds_remaining: i64,
+    },
     GameStarted {
         started_at: i64,
     },
+    /// The part of the race text the recipient may see so far
+    TextRevealed {
+        text_id: String,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        complete: bool,
+    },
     PlayerProgress {
         player_id: String,
         progress: u32,
         wpm: u32,
         accuracy: f32,
+        errors: u32,
     },
     PlayerFinished {
         player_id: String,
@@ -87,58 +536,309 @@ pub enum ServerMessage {
         accuracy: f32,
         finished_at: i64,
     },
+    TeamChanged {
+        player_id: String,
+        team: u32,
+    },
+    /// The race mode knocked a player out
+    Eliminated {
+        player_id: String,
+        eliminated_at: i64,
+    },
+    /// A round of a multi-round match ended; `next_round_at` is unset after
+    /// the last one
+    RoundFinished {
+        round: u32,
+        rounds: u32,
+        winner_id: Option<String>,
+        standings: Vec<Player>,
+        next_round_at: Option<i64>,
+    },
+    /// The next round is about to count down on a new text
+    RoundStarted {
+        round: u32,
+        game: GameState,
+    },
     GameFinished {
         winner_id: Option<String>,
         final_standings: Vec<Player>,
+        rating_changes: Vec<RatingChange>,
+        /// Only in team games
+        #[serde(skip_serializing_if = "Option::is_none")]
+        winning_team: Option<u32>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        team_standings: Vec<TeamStanding>,
+    },
+    GameClosed {
+        reason: String,
+    },
+    RematchVote {
+        player_id: String,
+        votes: usize,
+        needed: usize,
+    },
+    /// The game is back in the lobby with a new text and cleared stats
+    RematchStarted {
+        game: GameState,
     },
     Error {
+        code: String,
         message: String,
+        /// Game status that made the request invalid, if that was the reason
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<GameStatus>,
+    },
+    TimeSync {
+        client_sent_at: i64,
+        server_received_at: i64,
+        server_sent_at: i64,
     },
     Pong,
 }
 
 impl GameState {
-    pub fn new(id: String, code: String, difficulty: String, text: String, host_id: String) -> Self {
-        Self {
+    pub fn new(id: String, code: String, text: &Text, host_id: String, settings: GameSettings) -> Self {
+        let mut game = Self {
             id,
             code,
-            difficulty,
-            text,
+            difficulty: text.difficulty,
+            language: text.language.clone(),
+            text_id: String::new(),
+            text: String::new(),
+            text_length: 0,
+            text_source: None,
+            ticket_host_id: host_id.clone(),
             host_id,
+            settings,
             players: Vec::new(),
+            spectators: 0,
             status: GameStatus::Waiting,
+            round: 1,
             started_at: None,
+            banned_user_ids: HashSet::new(),
+            departed: Vec::new(),
+        };
+        game.set_text(text);
+        game
+    }
+
+    /// Race on the part of `text` the game's mode uses.
+    fn set_text(&mut self, text: &Text) {
+        let content = self.settings.mode.rules().race_text(&text.content);
+        self.text_id = text.id.clone();
+        self.text = content.to_string();
+        self.text_length = content.chars().count();
+        self.text_source = text.source.clone();
+    }
+
+    /// Seat `player` if the game's settings admit them, on the smallest team
+    /// in team games.
+    pub fn add_player(&mut self, player: Player, invited: bool) -> Result<(), GameError> {
+        self.admits(&player.user_id, invited)?;
+        self.seat(player);
+        Ok(())
+    }
+
+    /// Seat a bot if there is room. Bots are added by the host, so the
+    /// room's access rules do not apply to them.
+    pub fn add_bot(&mut self, bot: Player) -> Result<(), GameError> {
+        if self.players.len() >= self.settings.max_players {
+            return Err(GameError::RoomFull);
         }
+        self.seat(bot);
+        Ok(())
     }
 
-    pub fn add_player(&mut self, player: Player) -> bool {
+    fn seat(&mut self, mut player: Player) {
+        if self.settings.teams > 0 {
+            player.team = (1..=self.settings.teams).min_by_key(|&team| self.team_size(team));
+        }
         self.players.push(player);
-        true
     }
 
+    fn team_size(&self, team: u32) -> usize {
+        self.players.iter().filter(|p| p.team == Some(team)).count()
+    }
+
+    /// Move a player to `team`.
+    pub fn assign_team(&mut self, player_id: &str, team: u32) -> Result<(), GameError> {
+        if team == 0 || team > self.settings.teams {
+            return Err(GameError::InvalidTeam);
+        }
+        let player = self.get_player_mut(player_id).ok_or(GameError::PlayerNotFound)?;
+        player.team = Some(team);
+        Ok(())
+    }
+
+    /// Teams ranked by their members' average `score`, then by average WPM.
+    pub fn team_standings(&self, standings: &[Player]) -> Vec<TeamStanding> {
+        let mut teams: Vec<TeamStanding> = (1..=self.settings.teams)
+            .map(|team| {
+                let members: Vec<&Player> = standings.iter().filter(|p| p.team == Some(team)).collect();
+                TeamStanding {
+                    team,
+                    player_ids: members.iter().map(|p| p.id.clone()).collect(),
+                    score: members.iter().map(|p| p.score).sum::<u32>() as f64 / members.len().max(1) as f64,
+                    wpm: members.iter().map(|p| p.wpm).sum::<u32>() / members.len().max(1) as u32,
+                }
+            })
+            .filter(|team| !team.player_ids.is_empty())
+            .collect();
+        teams.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.wpm.cmp(&a.wpm)));
+        teams
+    }
+
+    fn admits(&self, user_id: &str, invited: bool) -> Result<(), GameError> {
+        if self.players.len() >= self.settings.max_players {
+            return Err(GameError::RoomFull);
+        }
+        let racing = matches!(self.status, GameStatus::Playing | GameStatus::Intermission);
+        if racing && !self.settings.allow_late_join {
+            return Err(GameError::LateJoinDisabled);
+        }
+        self.check_access(user_id, invited)
+    }
+
+    /// Whether `user_id` may be in this game at all, as a player or spectator.
+    /// `invited` is set for users holding a ticket to the game.
+    pub fn check_access(&self, user_id: &str, invited: bool) -> Result<(), GameError> {
+        if self.banned_user_ids.contains(user_id) {
+            return Err(GameError::Banned);
+        }
+        if !self.settings.private || invited || self.settings.allowed_user_ids.iter().any(|id| id == user_id) {
+            return Ok(());
+        }
+        Err(GameError::NotInvited)
+    }
+
+    /// Unseat a player. Leaving a race under way counts as not finishing it.
     pub fn remove_player(&mut self, player_id: &str) {
-        self.players.retain(|p| p.id != player_id);
+        let Some(index) = self.players.iter().position(|p| p.id == player_id) else {
+            return;
+        };
+        let mut player = self.players.remove(index);
+        if matches!(self.status, GameStatus::Playing | GameStatus::Intermission) {
+            player.left = true;
+            player.connected = false;
+            player.dnf |= !player.finished;
+            self.departed.push(player);
+        }
+    }
+
+    /// Everyone to rank when a race ends: the players still seated and those
+    /// who left on the way.
+    pub fn field(&self) -> GameState {
+        let mut field = self.clone();
+        field.players.extend(self.departed.iter().cloned());
+        field
     }
 
     pub fn get_player_mut(&mut self, player_id: &str) -> Option<&mut Player> {
         self.players.iter_mut().find(|p| p.id == player_id)
     }
 
-    pub fn start_game(&mut self) {
-        self.status = GameStatus::Playing;
-        self.started_at = Some(chrono::Utc::now().timestamp());
+    pub fn is_host(&self, player_id: &str) -> bool {
+        self.players.iter().any(|p| p.id == player_id && p.user_id == self.host_id)
+    }
+
+    /// Player who should take over as host: the one who joined earliest,
+    /// preferring anyone currently connected. Bots never host.
+    pub fn next_host(&self) -> Option<&Player> {
+        let mut humans = self.players.iter().filter(|p| !p.bot);
+        humans.clone().find(|p| p.connected).or_else(|| humans.next())
+    }
+
+    /// Move to `next`, refusing anything the state machine does not allow.
+    pub fn transition(&mut self, next: GameStatus) -> Result<(), GameError> {
+        if !self.status.can_transition_to(next) {
+            return Err(GameError::InvalidTransition {
+                from: self.status,
+                to: next,
+            });
+        }
+        self.status = next;
+        Ok(())
+    }
+
+    pub fn start_countdown(&mut self, start_at: i64) -> Result<(), GameError> {
+        self.transition(GameStatus::Countdown)?;
+        self.started_at = Some(start_at);
+        Ok(())
     }
 
-    pub fn check_all_finished(&self) -> bool {
-        !self.players.is_empty() && self.players.iter().all(|p| p.finished)
+    pub fn start_game(&mut self) -> Result<(), GameError> {
+        self.transition(GameStatus::Playing)
     }
 
-    pub fn get_winner(&self) -> Option<String> {
-        self.players
-            .iter()
-            .filter(|p| p.finished)
-            .min_by_key(|p| p.finished_at.unwrap_or(i64::MAX))
-            .map(|p| p.id.clone())
+    pub fn finish(&mut self) -> Result<(), GameError> {
+        self.transition(GameStatus::Finished)
+    }
+
+    pub fn is_last_round(&self) -> bool {
+        self.round >= self.settings.rounds
+    }
+
+    /// End a round that is not the last of the match.
+    pub fn finish_round(&mut self) -> Result<(), GameError> {
+        self.transition(GameStatus::Intermission)
+    }
+
+    /// Set up the next round of the match on `text`, keeping match scores.
+    /// The game stays in `Intermission` until its countdown starts.
+    pub fn next_round(&mut self, text: &Text) -> Result<(), GameError> {
+        if self.status != GameStatus::Intermission {
+            return Err(GameError::InvalidTransition {
+                from: self.status,
+                to: GameStatus::Countdown,
+            });
+        }
+        self.round += 1;
+        self.set_text(text);
+        self.started_at = None;
+        for player in &mut self.players {
+            player.reset_stats();
+        }
+        // Players who left sit out the remaining rounds
+        for player in &mut self.departed {
+            player.reset_stats();
+            player.dnf = true;
+        }
+        Ok(())
+    }
+
+    /// Score a race ranked as `standings`: of N players, the winner gets N
+    /// points, the runner-up N - 1 and so on, and anyone who did not finish
+    /// gets nothing. Points add up over the rounds of a match. Updates the
+    /// scores in `standings` too.
+    pub fn award_points(&mut self, standings: &mut [Player]) {
+        let count = standings.len() as u32;
+        for (place, standing) in standings.iter_mut().enumerate() {
+            if !standing.dnf {
+                standing.score += count - place as u32;
+            }
+            let mut everyone = self.players.iter_mut().chain(self.departed.iter_mut());
+            if let Some(player) = everyone.find(|p| p.id == standing.id) {
+                player.score = standing.score;
+            }
+        }
+    }
+
+    /// Put a finished game back in the lobby to race again on `text`.
+    pub fn rematch(&mut self, text: &Text) -> Result<(), GameError> {
+        self.transition(GameStatus::Waiting)?;
+        self.round = 1;
+        self.set_text(text);
+        self.started_at = None;
+        self.departed.clear();
+        for player in &mut self.players {
+            player.reset_stats();
+            player.score = 0;
+        }
+        Ok(())
+    }
+
+    pub fn abort(&mut self) -> Result<(), GameError> {
+        self.transition(GameStatus::Aborted)
     }
 }
 
@@ -151,8 +851,34 @@ impl Player {
             wpm: 0,
             accuracy: 0.0,
             progress: 0,
+            errors: 0,
             finished: false,
             finished_at: None,
+            dnf: false,
+            eliminated_at: None,
+            score: 0,
+            team: None,
+            bot: false,
+            left: false,
+            connected: true,
+            disconnected_at: None,
+            typing: TypingSession::default(),
+            revealed: 0,
         }
     }
+
+    /// Clear everything from the last race, keeping who the player is and
+    /// their match score.
+    pub fn reset_stats(&mut self) {
+        self.wpm = 0;
+        self.accuracy = 0.0;
+        self.progress = 0;
+        self.errors = 0;
+        self.finished = false;
+        self.finished_at = None;
+        self.dnf = false;
+        self.eliminated_at = None;
+        self.typing = TypingSession::default();
+        self.revealed = 0;
+    }
 }
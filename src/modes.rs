@@ -0,0 +1,499 @@
+use crate::config::Config;
+use crate::models::{GameError, GameState, Player, RaceMode, ServerMessage};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+/// The rules of a race: how long it runs, what happens as players type, when
+/// it is over and how it is ranked.
+///
+/// The game actor calls these hooks and otherwise knows nothing about modes,
+/// so a new mode is a `RaceMode` variant plus an implementation here. The
+/// provided methods are the classic rules.
+pub trait GameMode: Send {
+    /// The part of the picked text the race is run on.
+    fn race_text<'a>(&self, text: &'a str) -> &'a str {
+        text
+    }
+
+    /// Called as the race starts. Returns how long the race may run.
+    fn on_start(&mut self, _game: &mut GameState, config: &Config) -> Duration {
+        config.race_timeout
+    }
+
+    /// Characters of the text `player_id` types.
+    fn segment(&self, game: &GameState, _player_id: &str) -> Range<usize> {
+        0..game.text_length
+    }
+
+    /// Refuse keystrokes from a player who may not type yet.
+    fn check_turn(&self, _game: &GameState, _player_id: &str) -> Result<(), GameError> {
+        Ok(())
+    }
+
+    /// Called once keystrokes `player_id` typed at `at` have been applied to
+    /// their stats. Returns messages to broadcast, after their progress.
+    fn on_progress(&mut self, _game: &mut GameState, _player_id: &str, _at: i64) -> Vec<ServerMessage> {
+        Vec::new()
+    }
+
+    /// How often `on_tick` runs while the race is on, if the mode needs it.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called every `tick_interval` during the race, at `at`. Returns
+    /// messages to broadcast.
+    fn on_tick(&mut self, _game: &mut GameState, _at: i64) -> Vec<ServerMessage> {
+        Vec::new()
+    }
+
+    /// Called as the race ends, before it is ranked. Anyone still typing did
+    /// not finish.
+    fn on_finish(&mut self, game: &mut GameState) {
+        for player in game.players.iter_mut().filter(|p| !p.finished && p.eliminated_at.is_none()) {
+            player.dnf = true;
+        }
+    }
+
+    /// Whether nobody is left racing.
+    fn is_over(&self, game: &GameState) -> bool {
+        !game.players.is_empty() && game.players.iter().all(|p| p.finished || p.eliminated_at.is_some())
+    }
+
+    /// Finishers by finish time, then everyone else by how far they got, with
+    /// eliminated players and then those who left last.
+    fn rank(&self, game: &GameState) -> Vec<Player> {
+        let mut standings = game.players.clone();
+        standings.sort_by(|a, b| match (a.finished_at, b.finished_at) {
+            (Some(a_at), Some(b_at)) => a_at.cmp(&b_at),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a
+                .left
+                .cmp(&b.left)
+                .then(a.eliminated_at.is_some().cmp(&b.eliminated_at.is_some()))
+                .then(b.progress.cmp(&a.progress)),
+        });
+        standings
+    }
+
+    /// The winner from the ranked `standings`, if the top player finished.
+    fn winner(&self, standings: &[Player]) -> Option<String> {
+        standings.first().filter(|p| p.finished).map(|p| p.id.clone())
+    }
+}
+
+impl RaceMode {
+    /// The rules for one race in this mode.
+    pub fn rules(&self) -> Box<dyn GameMode> {
+        match *self {
+            RaceMode::Classic => Box::new(Classic),
+            RaceMode::Timed { seconds } => Box::new(Timed {
+                seconds,
+                text: Vec::new(),
+            }),
+            RaceMode::Words { count } => Box::new(Words { count }),
+            RaceMode::SuddenDeath => Box::new(SuddenDeath),
+            RaceMode::BattleRoyale { interval_seconds } => Box::new(BattleRoyale {
+                interval: Duration::from_secs(interval_seconds.max(1)),
+            }),
+            RaceMode::Relay => Box::<Relay>::default(),
+        }
+    }
+}
+
+pub struct Classic;
+
+impl GameMode for Classic {}
+
+/// Type for a fixed time; most characters typed correctly wins.
+pub struct Timed {
+    seconds: u64,
+    /// The race text, to count correct characters against
+    text: Vec<char>,
+}
+
+impl GameMode for Timed {
+    fn on_start(&mut self, game: &mut GameState, _config: &Config) -> Duration {
+        self.text = game.text.chars().collect();
+        Duration::from_secs(self.seconds)
+    }
+
+    /// Nobody fails to finish a race against the clock.
+    fn on_finish(&mut self, _game: &mut GameState) {}
+
+    fn rank(&self, game: &GameState) -> Vec<Player> {
+        let text: Vec<char> = game.text.chars().collect();
+        let mut standings = game.players.clone();
+        standings.sort_by(|a, b| {
+            a.left
+                .cmp(&b.left)
+                .then(b.typing.correct_chars(&text).cmp(&a.typing.correct_chars(&text)))
+                .then(a.finished_at.unwrap_or(i64::MAX).cmp(&b.finished_at.unwrap_or(i64::MAX)))
+                .then(a.errors.cmp(&b.errors))
+        });
+        standings
+    }
+
+    /// Progress is a whole percentage, so it stays 0 for the first few
+    /// characters of a long text; any correct character can win.
+    fn winner(&self, standings: &[Player]) -> Option<String> {
+        standings
+            .first()
+            .filter(|p| p.typing.correct_chars(&self.text) > 0)
+            .map(|p| p.id.clone())
+    }
+}
+
+/// Race on the first `count` words of the text.
+pub struct Words {
+    count: usize,
+}
+
+impl GameMode for Words {
+    fn race_text<'a>(&self, text: &'a str) -> &'a str {
+        let mut words = 0;
+        let mut in_word = false;
+        for (i, c) in text.char_indices() {
+            if c.is_whitespace() {
+                if in_word && words == self.count.max(1) {
+                    return &text[..i];
+                }
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+        }
+        text
+    }
+}
+
+/// Classic, except that typing on past a mistake instead of correcting it
+/// eliminates the player.
+pub struct SuddenDeath;
+
+impl GameMode for SuddenDeath {
+    fn on_progress(&mut self, game: &mut GameState, player_id: &str, at: i64) -> Vec<ServerMessage> {
+        match game.get_player_mut(player_id) {
+            Some(player) if player.typing.typed_past_error() => {
+                player.eliminated_at = Some(at);
+                vec![ServerMessage::Eliminated {
+                    player_id: player_id.to_string(),
+                    eliminated_at: at,
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Every `interval` the player furthest behind is eliminated, until one is
+/// left. Players who finish the text are safe.
+pub struct BattleRoyale {
+    interval: Duration,
+}
+
+impl GameMode for BattleRoyale {
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    fn on_tick(&mut self, game: &mut GameState, at: i64) -> Vec<ServerMessage> {
+        let survivors = game.players.iter().filter(|p| p.eliminated_at.is_none()).count();
+        if survivors <= 1 {
+            return Vec::new();
+        }
+        let last = game
+            .players
+            .iter_mut()
+            .filter(|p| !p.finished && p.eliminated_at.is_none())
+            .min_by_key(|p| (p.progress, p.wpm));
+        match last {
+            Some(player) => {
+                player.eliminated_at = Some(at);
+                vec![ServerMessage::Eliminated {
+                    player_id: player.id.clone(),
+                    eliminated_at: at,
+                }]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The last player standing won whether or not they finished the text;
+    /// only a race stopped by the time limit leaves survivors DNF.
+    fn on_finish(&mut self, game: &mut GameState) {
+        let survivors = game.players.iter().filter(|p| p.eliminated_at.is_none()).count();
+        if survivors > 1 {
+            Classic.on_finish(game);
+        }
+    }
+
+    fn is_over(&self, game: &GameState) -> bool {
+        let survivors: Vec<&Player> = game.players.iter().filter(|p| p.eliminated_at.is_none()).collect();
+        !game.players.is_empty() && (survivors.len() <= 1 || survivors.iter().all(|p| p.finished))
+    }
+
+    /// Survivors as in a classic race, then everyone eliminated, the last
+    /// to go first, then those who left.
+    fn rank(&self, game: &GameState) -> Vec<Player> {
+        let mut standings = Classic.rank(game);
+        standings.sort_by_key(|p| (p.left, p.eliminated_at.map(Reverse)));
+        standings
+    }
+
+    fn winner(&self, standings: &[Player]) -> Option<String> {
+        standings.first().filter(|p| p.eliminated_at.is_none()).map(|p| p.id.clone())
+    }
+}
+
+/// Each team types the text as a relay: it is split into one part per
+/// member and members type their part in join order, each starting once the
+/// teammate before them has finished. Players without a team run the whole
+/// text alone.
+#[derive(Default)]
+pub struct Relay {
+    /// Player ids of each team in turn order, fixed when the race starts
+    legs: Vec<Vec<String>>,
+    segments: HashMap<String, Range<usize>>,
+}
+
+impl GameMode for Relay {
+    fn on_start(&mut self, game: &mut GameState, config: &Config) -> Duration {
+        let mut teams: Vec<(Option<u32>, Vec<String>)> = Vec::new();
+        for player in &game.players {
+            match teams.iter_mut().find(|(team, _)| team.is_some() && *team == player.team) {
+                Some((_, members)) => members.push(player.id.clone()),
+                None => teams.push((player.team, vec![player.id.clone()])),
+            }
+        }
+
+        let text: Vec<char> = game.text.chars().collect();
+        self.legs.clear();
+        self.segments.clear();
+        for (_, members) in teams {
+            let mut segments = split(&text, members.len()).into_iter();
+            for member in &members {
+                match segments.next() {
+                    Some(segment) => {
+                        self.segments.insert(member.clone(), segment);
+                    }
+                    // More members than words: the rest have nothing to type
+                    None => {
+                        self.segments.insert(member.clone(), text.len()..text.len());
+                        let started_at = game.started_at;
+                        if let Some(player) = game.get_player_mut(member) {
+                            player.progress = 100;
+                            player.finished = true;
+                            player.finished_at = started_at;
+                        }
+                    }
+                }
+            }
+            self.legs.push(members);
+        }
+        config.race_timeout
+    }
+
+    /// Players who joined after the start run the whole text.
+    fn segment(&self, game: &GameState, player_id: &str) -> Range<usize> {
+        self.segments.get(player_id).cloned().unwrap_or(0..game.text_length)
+    }
+
+    /// Once a member has typed their part, the next teammate's turn, and
+    /// their clock, starts.
+    fn on_progress(&mut self, game: &mut GameState, player_id: &str, at: i64) -> Vec<ServerMessage> {
+        let text: Vec<char> = game.text.chars().collect();
+        let segment = self.segment(game, player_id);
+        let done = game.players.iter().any(|p| p.id == player_id && p.typing.is_complete(&text[segment.clone()]));
+        let leg = self.legs.iter().find(|leg| leg.iter().any(|id| id == player_id));
+        if let (true, Some(leg)) = (done, leg) {
+            let next = leg
+                .iter()
+                .skip_while(|id| *id != player_id)
+                .skip(1)
+                .find(|id| game.players.iter().any(|p| &p.id == *id && !p.finished));
+            if let Some(player) = next.cloned().and_then(|id| game.get_player_mut(&id)) {
+                player.typing.start(at);
+            }
+        }
+        Vec::new()
+    }
+
+    fn check_turn(&self, game: &GameState, player_id: &str) -> Result<(), GameError> {
+        let Some(leg) = self.legs.iter().find(|leg| leg.iter().any(|id| id == player_id)) else {
+            return Ok(());
+        };
+        // Teammates who left are skipped
+        let waiting = leg
+            .iter()
+            .take_while(|id| *id != player_id)
+            .any(|id| game.players.iter().any(|p| &p.id == id && !p.finished));
+        if waiting {
+            Err(GameError::NotYourTurn)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Teams by when their last member finished, then by how much of the
+    /// text they typed, with members in turn order. Teammates who left do not
+    /// hold their team back, as the rest of the team carried on without them.
+    fn rank(&self, game: &GameState) -> Vec<Player> {
+        let text: Vec<char> = game.text.chars().collect();
+        let mut teams: Vec<(Option<i64>, usize, Vec<Player>)> = self
+            .legs
+            .iter()
+            .map(|leg| {
+                let members: Vec<Player> = leg
+                    .iter()
+                    .filter_map(|id| game.players.iter().find(|p| &p.id == id))
+                    .cloned()
+                    .collect();
+                let finished_at = members
+                    .iter()
+                    .filter(|p| !p.left)
+                    .map(|p| p.finished_at)
+                    .collect::<Option<Vec<i64>>>()
+                    .and_then(|times| times.into_iter().max());
+                let typed = members
+                    .iter()
+                    .map(|p| p.typing.correct_chars(&text[self.segment(game, &p.id)]))
+                    .sum();
+                (finished_at, typed, members)
+            })
+            .collect();
+        teams.sort_by(|a, b| match (a.0, b.0) {
+            (Some(a_at), Some(b_at)) => a_at.cmp(&b_at),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => b.1.cmp(&a.1),
+        });
+
+        let mut standings: Vec<Player> = teams.into_iter().flat_map(|(_, _, members)| members).collect();
+        standings.extend(game.players.iter().filter(|p| !self.segments.contains_key(&p.id)).cloned());
+        standings
+    }
+
+    /// The first member of the top team still in the game, once the whole
+    /// team has finished.
+    fn winner(&self, standings: &[Player]) -> Option<String> {
+        let top = standings.first()?;
+        let leg = self.legs.iter().find(|leg| leg.contains(&top.id))?;
+        let racing: Vec<&Player> = standings.iter().filter(|p| leg.contains(&p.id) && !p.left).collect();
+        let first = racing.first()?;
+        racing.iter().all(|p| p.finished).then(|| first.id.clone())
+    }
+}
+
+/// Split `text` into at most `parts` runs of about equal length, each ending
+/// after whitespace so no word is cut in two. There are never more runs than
+/// words, so none is empty.
+fn split(text: &[char], parts: usize) -> Vec<Range<usize>> {
+    // Where every word but the first starts
+    let breaks: Vec<usize> = (1..text.len())
+        .filter(|&i| text[i - 1].is_whitespace() && !text[i].is_whitespace())
+        .collect();
+    let parts = parts.clamp(1, breaks.len() + 1);
+
+    let mut ranges = Vec::with_capacity(parts);
+    let mut start = 0;
+    let mut next = 0;
+    for part in 1..parts {
+        // Leave a break for each run still to come
+        let last = breaks.len() - (parts - 1 - part) - 1;
+        let target = text.len() * part / parts;
+        let mut i = next;
+        while i < last && breaks[i] < target {
+            i += 1;
+        }
+        ranges.push(start..breaks[i]);
+        start = breaks[i];
+        next = i + 1;
+    }
+    ranges.push(start..text.len());
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::GameSettings;
+    use crate::testing;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    #[test]
+    fn split_keeps_words_whole() {
+        let text = chars("the quick brown fox jumps over the lazy dog");
+        assert_eq!(split(&text, 3), vec![0..16, 16..31, 31..43]);
+    }
+
+    #[test]
+    fn split_never_makes_empty_runs() {
+        let text = chars("one two three");
+        assert_eq!(split(&text, 4), vec![0..4, 4..8, 8..13]);
+        assert_eq!(split(&text, 1), vec![0..13]);
+    }
+
+    #[test]
+    fn timed_race_is_won_by_a_few_correct_characters() {
+        let content = "the quick brown fox jumps over the lazy dog ".repeat(10);
+        let settings = GameSettings {
+            mode: RaceMode::Timed { seconds: 60 },
+            ..GameSettings::default()
+        };
+        let mut game = testing::game(&content, settings.clone(), 2);
+        let mut timed = settings.mode.rules();
+        timed.on_start(&mut game, &testing::config());
+        assert_eq!(timed.winner(&timed.rank(&game)), None);
+
+        let text = chars(&game.text);
+        game.players[1].typing.apply(&text, "the");
+        game.players[1].progress = game.players[1].typing.progress(&text);
+        assert_eq!(game.players[1].progress, 0);
+        assert_eq!(timed.winner(&timed.rank(&game)), Some("player-2".to_string()));
+    }
+
+    fn relay(teams: u32) -> GameSettings {
+        GameSettings {
+            mode: RaceMode::Relay,
+            teams,
+            ..GameSettings::default()
+        }
+    }
+
+    #[test]
+    fn relay_members_without_a_leg_are_done() {
+        let mut game = testing::game("one two three", relay(1), 4);
+        let mut relay = Relay::default();
+        relay.on_start(&mut game, &testing::config());
+        assert_eq!(relay.segment(&game, "player-3"), 8..13);
+        assert_eq!(relay.segment(&game, "player-4"), 13..13);
+        assert!(game.players[3].finished);
+        assert!(!game.players[2].finished);
+    }
+
+    #[test]
+    fn relay_legs_are_timed_from_their_turn() {
+        let mut game = testing::game("one two three four", relay(1), 2);
+        let mut relay = Relay::default();
+        relay.on_start(&mut game, &testing::config());
+        let text = chars("one two three four");
+        let leg = &text[relay.segment(&game, "player-1")];
+        assert_eq!(leg.iter().collect::<String>(), "one two three ");
+
+        game.players[0].typing.apply(leg, "one two three");
+        relay.on_progress(&mut game, "player-1", 5_000);
+        assert_eq!(game.players[1].typing.started_at(), None);
+
+        game.players[0].typing.apply(leg, " ");
+        relay.on_progress(&mut game, "player-1", 6_000);
+        assert_eq!(game.players[1].typing.started_at(), Some(6_000));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::Config;
+use crate::models::Player;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// Rating every user starts from.
+pub const INITIAL_RATING: f64 = 1000.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRating {
+    pub user_id: String,
+    pub username: String,
+    pub rating: f64,
+    pub races: u32,
+    pub updated_at: i64,
+}
+
+/// How one race moved a player's rating, sent with `GameFinished`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingChange {
+    pub player_id: String,
+    pub user_id: String,
+    pub before: f64,
+    pub after: f64,
+    pub delta: f64,
+}
+
+/// Elo ratings per user, updated from race standings and kept in a JSON file.
+///
+/// A race of N players is scored as every pair of players having played one
+/// game, the higher placed player winning, with each player's total scaled by
+/// `1 / (N - 1)` so a race moves ratings about as much as a single duel.
+#[derive(Clone)]
+pub struct RatingStore {
+    path: PathBuf,
+    k_factor: f64,
+    ratings: Arc<Mutex<HashMap<String, UserRating>>>,
+    /// Serializes file writes so an older snapshot never overwrites a newer one
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl RatingStore {
+    pub fn load(config: &Config) -> Self {
+        let path = config.ratings_path.clone();
+        let ratings: HashMap<String, UserRating> = match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<UserRating>>(&bytes) {
+                Ok(list) => list.into_iter().map(|r| (r.user_id.clone(), r)).collect(),
+                Err(e) => panic!("Cannot parse ratings file {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => panic!("Cannot read ratings file {}: {}", path.display(), e),
+        };
+        info!("Loaded {} ratings from {}", ratings.len(), path.display());
+
+        Self {
+            path,
+            k_factor: config.rating_k_factor,
+            ratings: Arc::new(Mutex::new(ratings)),
+            save_lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub fn get(&self, user_id: &str) -> Option<UserRating> {
+        self.ratings.lock().unwrap().get(user_id).cloned()
+    }
+
+    /// Highest rated users first.
+    pub fn leaderboard(&self, limit: usize) -> Vec<UserRating> {
+        let mut all: Vec<UserRating> = self.ratings.lock().unwrap().values().cloned().collect();
+        all.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        all.truncate(limit);
+        all
+    }
+
+    /// Apply one race's result, best placed first, and persist the new ratings.
+    /// Races with fewer than two distinct users are unrated.
+    pub async fn record_race(&self, standings: &[Player]) -> Vec<RatingChange> {
+        // A user with two seats is rated on their better placement only, and
+        // bots are not rated at all
+        let mut seen = HashSet::new();
+        let ranked: Vec<&Player> = standings
+            .iter()
+            .filter(|p| !p.bot && seen.insert(p.user_id.clone()))
+            .collect();
+        if ranked.len() < 2 {
+            return Vec::new();
+        }
+
+        let changes = {
+            let mut ratings = self.ratings.lock().unwrap();
+            let before: Vec<f64> = ranked
+                .iter()
+                .map(|p| ratings.get(&p.user_id).map(|r| r.rating).unwrap_or(INITIAL_RATING))
+                .collect();
+
+            let mut deltas = vec![0.0; ranked.len()];
+            let scale = self.k_factor / (ranked.len() - 1) as f64;
+            for winner in 0..ranked.len() {
+                for loser in winner + 1..ranked.len() {
+                    let expected = 1.0 / (1.0 + 10f64.powf((before[loser] - before[winner]) / 400.0));
+                    deltas[winner] += scale * (1.0 - expected);
+                    deltas[loser] -= scale * (1.0 - expected);
+                }
+            }
+
+            let now = chrono::Utc::now().timestamp_millis();
+            ranked
+                .iter()
+                .zip(before.iter().zip(deltas))
+                .map(|(player, (&before, delta))| {
+                    let entry = ratings.entry(player.user_id.clone()).or_insert_with(|| UserRating {
+                        user_id: player.user_id.clone(),
+                        username: player.username.clone(),
+                        rating: INITIAL_RATING,
+                        races: 0,
+                        updated_at: now,
+                    });
+                    entry.username = player.username.clone();
+                    entry.rating = before + delta;
+                    entry.races += 1;
+                    entry.updated_at = now;
+
+                    RatingChange {
+                        player_id: player.id.clone(),
+                        user_id: player.user_id.clone(),
+                        before,
+                        after: entry.rating,
+                        delta,
+                    }
+                })
+                .collect()
+        };
+
+        self.save().await;
+        changes
+    }
+
+    async fn save(&self) {
+        let _guard = self.save_lock.lock().await;
+        let body = {
+            let ratings = self.ratings.lock().unwrap();
+            let list: Vec<&UserRating> = ratings.values().collect();
+            serde_json::to_vec_pretty(&list).unwrap()
+        };
+
+        // Write then rename so a crash never leaves a half-written file behind
+        let temp = self.path.with_extension("tmp");
+        let written = async {
+            tokio::fs::write(&temp, &body).await?;
+            tokio::fs::rename(&temp, &self.path).await
+        };
+        if let Err(e) = written.await {
+            error!("Failed to save ratings to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn deltas(changes: &[RatingChange]) -> Vec<f64> {
+        changes.iter().map(|c| c.delta.round()).collect()
+    }
+
+    #[tokio::test]
+    async fn winner_takes_rating_from_loser() {
+        let ratings = RatingStore::load(&testing::config());
+        let changes = ratings.record_race(&[testing::player(1), testing::player(2)]).await;
+        assert_eq!(deltas(&changes), vec![16.0, -16.0]);
+        assert_eq!(ratings.get("user-1").unwrap().rating, 1016.0);
+        assert_eq!(ratings.get("user-2").unwrap().races, 1);
+    }
+
+    #[tokio::test]
+    async fn race_is_scored_pairwise() {
+        let ratings = RatingStore::load(&testing::config());
+        let standings = [testing::player(1), testing::player(2), testing::player(3)];
+        let changes = ratings.record_race(&standings).await;
+        // Each pair is half a game at K = 32
+        assert_eq!(deltas(&changes), vec![16.0, 0.0, -16.0]);
+
+        // The favourite now gains less for beating the same field
+        let changes = ratings.record_race(&standings).await;
+        assert!(changes[0].delta < 16.0);
+        assert!(changes[2].delta > -16.0);
+    }
+
+    #[tokio::test]
+    async fn bots_and_second_seats_are_not_rated() {
+        let ratings = RatingStore::load(&testing::config());
+        let mut bot = testing::player(3);
+        bot.bot = true;
+        let mut second_seat = testing::player(4);
+        second_seat.user_id = "user-1".to_string();
+
+        let changes = ratings.record_race(&[testing::player(1), bot, second_seat, testing::player(2)]).await;
+        assert_eq!(changes.len(), 2);
+        assert_eq!(ratings.get("user-3").map(|r| r.races), None);
+
+        // One user racing a bot is unrated
+        let mut bot = testing::player(5);
+        bot.bot = true;
+        assert!(ratings.record_race(&[testing::player(6), bot]).await.is_empty());
+    }
+}
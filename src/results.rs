@@ -0,0 +1,291 @@
+use crate::config::Config;
+use crate::difficulty::Difficulty;
+use crate::models::{GameState, Player, RaceMode};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Deliveries tried per result before it is set aside in `failed/`
+const MAX_ATTEMPTS: u32 = 20;
+
+/// Final standings of one race, as posted to Laravel.
+#[derive(Debug, Serialize)]
+pub struct RaceResult {
+    /// Unique per report so Laravel can ignore retried deliveries
+    pub id: String,
+    pub game_id: String,
+    pub game_code: String,
+    pub difficulty: Difficulty,
+    pub language: String,
+    pub text_id: String,
+    pub mode: RaceMode,
+    /// Round of the match this race was
+    pub round: u32,
+    pub started_at: Option<i64>,
+    pub finished_at: i64,
+    pub winner_id: Option<String>,
+    pub standings: Vec<Standing>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Standing {
+    pub place: u32,
+    pub player_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub wpm: u32,
+    pub accuracy: f32,
+    pub errors: u32,
+    pub progress: u32,
+    pub finished: bool,
+    pub finished_at: Option<i64>,
+    pub dnf: bool,
+    pub eliminated_at: Option<i64>,
+    pub bot: bool,
+}
+
+impl RaceResult {
+    pub fn new(game: &GameState, winner_id: Option<String>, standings: &[Player]) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            game_id: game.id.clone(),
+            game_code: game.code.clone(),
+            difficulty: game.difficulty,
+            language: game.language.clone(),
+            text_id: game.text_id.clone(),
+            mode: game.settings.mode,
+            round: game.round,
+            started_at: game.started_at,
+            finished_at: chrono::Utc::now().timestamp_millis(),
+            winner_id,
+            standings: standings
+                .iter()
+                .enumerate()
+                .map(|(index, player)| Standing {
+                    place: index as u32 + 1,
+                    player_id: player.id.clone(),
+                    user_id: player.user_id.clone(),
+                    username: player.username.clone(),
+                    wpm: player.wpm,
+                    accuracy: player.accuracy,
+                    errors: player.errors,
+                    progress: player.progress,
+                    finished: player.finished,
+                    finished_at: player.finished_at,
+                    dnf: player.dnf,
+                    eliminated_at: player.eliminated_at,
+                    bot: player.bot,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Delivers race results to Laravel through an on-disk outbox.
+///
+/// Every result is written to the outbox directory before delivery is
+/// attempted and only deleted once Laravel accepts it, so results survive a
+/// crash or an outage and are retried on the next start.
+#[derive(Clone)]
+pub struct ResultReporter {
+    outbox: PathBuf,
+    queue: mpsc::UnboundedSender<PathBuf>,
+}
+
+impl ResultReporter {
+    /// Start the delivery worker, or return `None` if no Laravel URL is configured.
+    pub fn start(config: &Config) -> Option<Self> {
+        let Some(url) = config.results_url.clone() else {
+            warn!("LARAVEL_API_URL not set, race results will not be reported");
+            return None;
+        };
+
+        let outbox = config.results_outbox.clone();
+        if let Err(e) = std::fs::create_dir_all(outbox.join("failed")) {
+            error!("Cannot create results outbox {}: {}", outbox.display(), e);
+            return None;
+        }
+
+        let (queue, rx) = mpsc::unbounded_channel();
+
+        // Pick up anything left undelivered by a previous run
+        for path in pending_results(&outbox) {
+            let _ = queue.send(path);
+        }
+
+        let worker = Worker {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Failed to build HTTP client"),
+            url,
+            secret: config.results_secret.clone(),
+            outbox: outbox.clone(),
+        };
+        tokio::spawn(worker.run(rx));
+
+        Some(Self { outbox, queue })
+    }
+
+    pub async fn report(&self, result: RaceResult) {
+        let path = self.outbox.join(format!("{}.json", result.id));
+        let temp = path.with_extension("tmp");
+        let body = serde_json::to_vec(&result).unwrap();
+
+        // Write then rename so a crash never leaves a half-written result behind
+        let written = async {
+            tokio::fs::write(&temp, &body).await?;
+            tokio::fs::rename(&temp, &path).await
+        };
+        if let Err(e) = written.await {
+            error!("Failed to write result for game {} to outbox: {}", result.game_id, e);
+            return;
+        }
+
+        let _ = self.queue.send(path);
+    }
+}
+
+fn pending_results(outbox: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(outbox)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Oldest first, so results are delivered in the order races finished
+    paths.sort_by_key(|path| path.metadata().and_then(|m| m.modified()).ok());
+    paths
+}
+
+enum Delivery {
+    Delivered,
+    Rejected(String),
+    Retry(String),
+}
+
+struct Worker {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    outbox: PathBuf,
+}
+
+impl Worker {
+    async fn run(self, mut rx: mpsc::UnboundedReceiver<PathBuf>) {
+        // Results waiting for delivery, with how many attempts each has had.
+        // One that fails goes to the back, so it cannot hold up the others.
+        let mut queue: VecDeque<(PathBuf, u32)> = VecDeque::new();
+        let mut retry = None;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            while let Ok(path) = rx.try_recv() {
+                queue.push_back((path, 0));
+            }
+            // Behind anything that arrived while it was failing
+            queue.extend(retry.take());
+            let (path, attempts) = match queue.pop_front() {
+                Some(entry) => entry,
+                None => match rx.recv().await {
+                    Some(path) => (path, 0),
+                    None => break,
+                },
+            };
+
+            let body = match tokio::fs::read(&path).await {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Cannot read outbox entry {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            match self.deliver(&body).await {
+                Delivery::Delivered => {
+                    info!("Reported result {}", path.display());
+                    let _ = tokio::fs::remove_file(&path).await;
+                    backoff = INITIAL_BACKOFF;
+                }
+                Delivery::Rejected(reason) => {
+                    // Retrying will not help, park it for manual inspection
+                    error!("Laravel rejected result {}: {}", path.display(), reason);
+                    self.park(&path).await;
+                }
+                Delivery::Retry(reason) if attempts + 1 >= MAX_ATTEMPTS => {
+                    error!(
+                        "Giving up on result {} after {} attempts: {}",
+                        path.display(),
+                        MAX_ATTEMPTS,
+                        reason
+                    );
+                    self.park(&path).await;
+                }
+                Delivery::Retry(reason) => {
+                    warn!(
+                        "Failed to report result {} ({}), retrying in {:?}",
+                        path.display(),
+                        reason,
+                        backoff
+                    );
+                    retry = Some((path, attempts + 1));
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Move a result that will not be delivered to `failed/`.
+    async fn park(&self, path: &Path) {
+        if let Some(name) = path.file_name() {
+            let _ = tokio::fs::rename(path, self.outbox.join("failed").join(name)).await;
+        }
+    }
+
+    async fn deliver(&self, body: &[u8]) -> Delivery {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = sign(&self.secret, &timestamp, body);
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header("X-Timestamp", &timestamp)
+            .header("X-Signature", format!("sha256={}", signature))
+            .body(body.to_vec())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => Delivery::Delivered,
+            Ok(response)
+                if response.status().is_client_error()
+                    && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                Delivery::Rejected(response.status().to_string())
+            }
+            Ok(response) => Delivery::Retry(response.status().to_string()),
+            Err(e) => Delivery::Retry(e.to_string()),
+        }
+    }
+}
+
+/// HMAC-SHA256 over `"{timestamp}.{body}"`, hex encoded.
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
@@ -1,103 +1,126 @@
-use crate::models::GameState;
+use crate::config::Config;
+use crate::game::GameHandle;
+use crate::matchmaking::Matchmaker;
+use crate::ratings::RatingStore;
+use crate::results::ResultReporter;
+use crate::texts::TextCorpus;
 use dashmap::DashMap;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
 
-pub type Tx = mpsc::UnboundedSender<String>;
+/// Outgoing frames for one player's socket.
+pub type Tx = mpsc::UnboundedSender<Message>;
 pub type GameId = String;
-pub type PlayerId = String;
 
+/// Shared server state. Each game's `GameState` is owned by its own task;
+/// this only holds handles for reaching those tasks.
 #[derive(Clone)]
 pub struct AppState {
-    pub games: Arc<DashMap<GameId, GameState>>,
-    pub player_connections: Arc<DashMap<PlayerId, PlayerConnection>>,
-    pub game_players: Arc<DashMap<GameId, Vec<PlayerId>>>,
+    pub games: Arc<DashMap<GameId, GameHandle>>,
+    pub sessions: Arc<DashMap<String, Session>>,
+    pub config: Arc<Config>,
+    pub reporter: Option<ResultReporter>,
+    pub texts: Arc<TextCorpus>,
+    pub ratings: RatingStore,
+    pub matchmaker: Matchmaker,
 }
 
-#[allow(dead_code)]
-pub struct PlayerConnection {
+/// Seat held for a player, looked up by resume token when they reconnect.
+#[derive(Debug, Clone)]
+pub struct Session {
     pub player_id: String,
     pub user_id: String,
     pub game_id: String,
-    pub tx: Tx,
 }
 
-impl AppState {
-    pub fn new() -> Self {
-        Self {
-            games: Arc::new(DashMap::new()),
-            player_connections: Arc::new(DashMap::new()),
-            game_players: Arc::new(DashMap::new()),
+/// Number of round-trip samples kept per connection.
+const RTT_SAMPLES: usize = 8;
+
+/// Round-trip times measured from time sync exchanges on one connection.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    pending_sync: Option<i64>,
+    samples: VecDeque<i64>,
+}
+
+impl LatencyTracker {
+    /// Remember the `server_sent_at` of the reply we are about to send.
+    pub fn sync_sent(&mut self, server_sent_at: i64) {
+        self.pending_sync = Some(server_sent_at);
+    }
+
+    /// Record a round trip when the client acknowledges our latest reply.
+    /// Acks for anything but the outstanding reply are ignored.
+    pub fn sync_acked(&mut self, server_sent_at: i64, now: i64) {
+        if self.pending_sync != Some(server_sent_at) {
+            return;
         }
+        self.pending_sync = None;
+
+        if self.samples.len() == RTT_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(now - server_sent_at);
     }
 
-    pub fn add_game(&self, game: GameState) {
-        self.games.insert(game.id.clone(), game);
+    /// Median round-trip time in milliseconds, if any samples exist.
+    pub fn rtt(&self) -> Option<i64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<i64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        Some(sorted[sorted.len() / 2])
     }
 
-    pub fn get_game(&self, game_id: &str) -> Option<GameState> {
-        self.games.get(game_id).map(|g| g.clone())
+    /// Estimated one-way latency in milliseconds, capped at `cap_ms` so a
+    /// client cannot buy time by delaying its acks.
+    pub fn compensation(&self, cap_ms: i64) -> i64 {
+        self.rtt().map(|rtt| (rtt / 2).clamp(0, cap_ms)).unwrap_or(0)
     }
+}
 
-    pub fn remove_game(&self, game_id: &str) -> Option<GameState> {
-        self.game_players.remove(game_id);
-        self.games.remove(game_id).map(|(_, game)| game)
+impl AppState {
+    pub fn new(
+        config: Config,
+        reporter: Option<ResultReporter>,
+        texts: TextCorpus,
+        ratings: RatingStore,
+        matchmaker: Matchmaker,
+    ) -> Self {
+        Self {
+            games: Arc::new(DashMap::new()),
+            sessions: Arc::new(DashMap::new()),
+            config: Arc::new(config),
+            reporter,
+            texts: Arc::new(texts),
+            ratings,
+            matchmaker,
+        }
     }
 
-    pub fn update_game<F>(&self, game_id: &str, f: F) -> Option<GameState>
-    where
-        F: FnOnce(&mut GameState),
-    {
-        self.games.get_mut(game_id).map(|mut game| {
-            f(&mut game);
-            game.clone()
-        })
+    pub fn get_game(&self, game_id: &str) -> Option<GameHandle> {
+        self.games.get(game_id).map(|g| g.clone())
     }
 
-    pub fn add_player_connection(&self, player_id: String, connection: PlayerConnection) {
-        let game_id = connection.game_id.clone();
-        self.player_connections.insert(player_id.clone(), connection);
-        
-        self.game_players
-            .entry(game_id)
-            .or_insert_with(Vec::new)
-            .push(player_id);
+    pub fn create_session(&self, session: Session) -> String {
+        let resume_token = Uuid::new_v4().to_string();
+        self.sessions.insert(resume_token.clone(), session);
+        resume_token
     }
 
-    pub fn remove_player_connection(&self, player_id: &str) -> Option<String> {
-        if let Some((_, conn)) = self.player_connections.remove(player_id) {
-            let game_id = conn.game_id.clone();
-            
-            // Remove from game_players
-            if let Some(mut players) = self.game_players.get_mut(&game_id) {
-                players.retain(|id| id != player_id);
-            }
-            
-            return Some(game_id);
-        }
-        None
+    pub fn get_session(&self, resume_token: &str) -> Option<Session> {
+        self.sessions.get(resume_token).map(|s| s.clone())
     }
 
-    pub fn broadcast_to_game(&self, game_id: &str, message: &str, exclude_player: Option<&str>) {
-        if let Some(player_ids) = self.game_players.get(game_id) {
-            for player_id in player_ids.iter() {
-                if let Some(exclude) = exclude_player {
-                    if player_id == exclude {
-                        continue;
-                    }
-                }
-                
-                if let Some(conn) = self.player_connections.get(player_id) {
-                    let _ = conn.tx.send(message.to_string());
-                }
-            }
-        }
+    pub fn remove_sessions_for_player(&self, player_id: &str) {
+        self.sessions.retain(|_, session| session.player_id != player_id);
     }
 
-    #[allow(dead_code)]
-    pub fn send_to_player(&self, player_id: &str, message: &str) {
-        if let Some(conn) = self.player_connections.get(player_id) {
-            let _ = conn.tx.send(message.to_string());
-        }
+    pub fn remove_sessions_for_game(&self, game_id: &str) {
+        self.sessions.retain(|_, session| session.game_id != game_id);
     }
 }
@@ -0,0 +1,61 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::Config;
+use crate::difficulty::Difficulty;
+use crate::models::{GameSettings, GameState, Player};
+use crate::texts::Text;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Settings with the documented defaults, independent of the environment.
+pub fn config() -> Config {
+    Config {
+        reconnect_grace: Duration::from_secs(30),
+        countdown: Duration::from_secs(3),
+        race_timeout: Duration::from_secs(300),
+        lobby_timeout: Duration::from_secs(600),
+        round_intermission: Duration::from_secs(10),
+        rematch_timeout: Duration::from_secs(120),
+        max_latency_compensation: Duration::from_millis(150),
+        max_wpm: 300,
+        results_url: None,
+        results_secret: String::new(),
+        results_outbox: std::env::temp_dir().join("qcxis-test-outbox"),
+        texts_dir: PathBuf::from("texts"),
+        // Tests run in parallel, so each gets a ratings file of its own
+        ratings_path: std::env::temp_dir().join(format!("qcxis-test-ratings-{}.json", Uuid::new_v4())),
+        rating_k_factor: 32.0,
+        match_min_players: 2,
+        match_max_players: 4,
+        match_fill_wait: Duration::from_secs(10),
+        match_rating_window: 100,
+        match_window_growth: 20,
+    }
+}
+
+pub fn text(content: &str) -> Text {
+    Text {
+        id: "test".to_string(),
+        language: "en".to_string(),
+        difficulty: Difficulty::Easy,
+        source: None,
+        content: content.to_string(),
+    }
+}
+
+/// A player seated as `player-{n}` for `user-{n}`.
+pub fn player(n: usize) -> Player {
+    Player::new(format!("player-{}", n), format!("user-{}", n), format!("User {}", n))
+}
+
+/// A game on `content` hosted by `user-1`, with `players` seated as
+/// `player-1`/`user-1` and so on.
+pub fn game(content: &str, settings: GameSettings, players: usize) -> GameState {
+    let host_id = "user-1".to_string();
+    let mut game = GameState::new("game".to_string(), "CODE".to_string(), &text(content), host_id, settings);
+    for n in 1..=players {
+        game.add_player(player(n), false).unwrap();
+    }
+    game
+}
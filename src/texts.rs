@@ -0,0 +1,150 @@
+use crate::config::Config;
+use crate::difficulty::{self, Difficulty};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Language assumed for texts and tickets that do not name one.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+pub fn default_language() -> String {
+    DEFAULT_LANGUAGE.to_string()
+}
+
+/// One curated race text.
+#[derive(Debug, Clone)]
+pub struct Text {
+    pub id: String,
+    pub language: String,
+    pub difficulty: Difficulty,
+    /// Attribution, such as the work and author the passage is taken from
+    pub source: Option<String>,
+    pub content: String,
+}
+
+/// A text as written in a corpus file. Entries without a difficulty are
+/// tagged by the analyzer.
+#[derive(Deserialize)]
+struct TextEntry {
+    id: String,
+    #[serde(default = "default_language")]
+    language: String,
+    #[serde(default)]
+    difficulty: Option<Difficulty>,
+    #[serde(default)]
+    source: Option<String>,
+    content: String,
+}
+
+impl From<TextEntry> for Text {
+    fn from(entry: TextEntry) -> Self {
+        let analysis = difficulty::analyze(&entry.content);
+        let difficulty = match entry.difficulty {
+            Some(tagged) if tagged != analysis.difficulty => {
+                warn!(
+                    "Text {} is tagged {} but scores {:.0}, which is {}",
+                    entry.id, tagged, analysis.score, analysis.difficulty
+                );
+                tagged
+            }
+            Some(tagged) => tagged,
+            None => analysis.difficulty,
+        };
+
+        Self {
+            id: entry.id,
+            language: entry.language,
+            difficulty,
+            source: entry.source,
+            content: entry.content,
+        }
+    }
+}
+
+/// TOML files list their texts as `[[texts]]` tables.
+#[derive(Deserialize)]
+struct TomlFile {
+    texts: Vec<TextEntry>,
+}
+
+/// The race texts games are played on, loaded once at startup.
+///
+/// Every `.json` file in the texts directory holds an array of texts and
+/// every `.toml` file an array of `[[texts]]` tables. Clients never supply
+/// text themselves; games are always created on a text picked from here.
+#[derive(Debug, Default)]
+pub struct TextCorpus {
+    texts: Vec<Text>,
+}
+
+impl TextCorpus {
+    pub fn load(config: &Config) -> Self {
+        let dir = &config.texts_dir;
+        let mut paths: Vec<_> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Texts directory {} does not exist, no games can be created", dir.display());
+                return Self::default();
+            }
+            Err(e) => panic!("Cannot read texts directory {}: {}", dir.display(), e),
+        };
+        paths.sort();
+
+        let mut texts = Vec::new();
+        for path in paths {
+            texts.extend(read_file(&path));
+        }
+
+        let mut ids = HashSet::new();
+        for text in &texts {
+            if !ids.insert(text.id.as_str()) {
+                panic!("Duplicate text id {} in {}", text.id, dir.display());
+            }
+            if text.content.trim().is_empty() {
+                panic!("Text {} in {} is empty", text.id, dir.display());
+            }
+        }
+
+        info!("Loaded {} texts from {}", texts.len(), dir.display());
+        Self { texts }
+    }
+
+    /// Whether any text matches `difficulty` and `language`.
+    pub fn has(&self, difficulty: Difficulty, language: &str) -> bool {
+        self.texts.iter().any(|t| t.difficulty == difficulty && t.language == language)
+    }
+
+    /// A random text of `difficulty` in `language`, avoiding `exclude` when
+    /// there is anything else to choose from.
+    pub fn pick(&self, difficulty: Difficulty, language: &str, exclude: Option<&str>) -> Option<&Text> {
+        let matching: Vec<&Text> = self
+            .texts
+            .iter()
+            .filter(|t| t.difficulty == difficulty && t.language == language)
+            .collect();
+        let fresh: Vec<&Text> = matching.iter().copied().filter(|t| Some(t.id.as_str()) != exclude).collect();
+
+        let mut rng = rand::thread_rng();
+        fresh.choose(&mut rng).or_else(|| matching.choose(&mut rng)).copied()
+    }
+}
+
+/// Texts in one corpus file. Files of other types are skipped.
+fn read_file(path: &Path) -> Vec<Text> {
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice::<Vec<TextEntry>>(&bytes).map_err(|e| e.to_string())),
+        Some("toml") => std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|body| toml::from_str::<TomlFile>(&body).map(|file| file.texts).map_err(|e| e.to_string())),
+        _ => return Vec::new(),
+    };
+    parsed
+        .unwrap_or_else(|e| panic!("Cannot load texts from {}: {}", path.display(), e))
+        .into_iter()
+        .map(Text::from)
+        .collect()
+}
@@ -0,0 +1,172 @@
+/// Character a client sends inside `keys` to delete the previous character.
+pub const BACKSPACE: char = '\u{8}';
+
+/// Keystrokes allowed on top of the speed limit, for batches arriving close
+/// together.
+const KEYSTROKE_ALLOWANCE: f64 = 10.0;
+
+/// How much typing time at the speed limit can be saved up, so a player who
+/// sits idle cannot spend the wait on one pasted batch.
+const BURST_MS: f64 = 2_000.0;
+
+/// Server-side record of what a player has typed so far.
+///
+/// Every keystroke is checked against the race text, so progress, WPM and
+/// accuracy are derived here instead of being trusted from the client.
+#[derive(Debug, Clone, Default)]
+pub struct TypingSession {
+    typed: Vec<char>,
+    keystrokes: u32,
+    errors: u32,
+    /// Position of the earliest wrong character still in `typed`
+    first_error: Option<usize>,
+    /// Whether a character was ever typed after a mistake that had not been deleted
+    typed_past_error: bool,
+    /// Keystrokes that may still be typed before the speed limit is reached,
+    /// as of `budget_at`
+    budget: f64,
+    budget_at: Option<i64>,
+    /// When the player started typing, if not when the race did
+    started_at: Option<i64>,
+}
+
+impl TypingSession {
+    /// Time this session from `at` instead of from the race start.
+    pub fn start(&mut self, at: i64) {
+        self.started_at = Some(at);
+    }
+
+    pub fn started_at(&self) -> Option<i64> {
+        self.started_at
+    }
+
+    /// Apply a batch of keystrokes. Input past the end of the text is ignored.
+    pub fn apply(&mut self, text: &[char], keys: &str) {
+        for key in keys.chars() {
+            if key == BACKSPACE {
+                self.typed.pop();
+                if self.first_error.is_some_and(|at| at >= self.typed.len()) {
+                    self.first_error = None;
+                }
+                continue;
+            }
+
+            let position = self.typed.len();
+            if position >= text.len() {
+                break;
+            }
+
+            self.keystrokes += 1;
+            if self.first_error.is_some() {
+                self.typed_past_error = true;
+            }
+            if text[position] != key {
+                self.errors += 1;
+                self.first_error.get_or_insert(position);
+            }
+            self.typed.push(key);
+        }
+    }
+
+    /// Whether `keys`, typed at `at`, come faster than `max_wpm` allows. The
+    /// allowance refills at that speed but only holds a short burst; keys
+    /// that are not too fast are taken from it.
+    pub fn too_fast(&mut self, keys: &str, at: i64, max_wpm: u32) -> bool {
+        let pending = keys.chars().filter(|&key| key != BACKSPACE).count() as f64;
+        // A word is five characters
+        let per_ms = max_wpm as f64 * 5.0 / 60_000.0;
+        let capacity = per_ms * BURST_MS + KEYSTROKE_ALLOWANCE;
+        let budget = match self.budget_at {
+            Some(last) => (self.budget + per_ms * (at - last).max(0) as f64).min(capacity),
+            None => capacity,
+        };
+        if pending > budget {
+            return true;
+        }
+        self.budget = budget - pending;
+        self.budget_at = Some(self.budget_at.map_or(at, |last| last.max(at)));
+        false
+    }
+
+    /// Number of characters from the start of the text typed correctly.
+    pub fn correct_chars(&self, text: &[char]) -> usize {
+        self.typed
+            .iter()
+            .zip(text.iter())
+            .take_while(|(typed, expected)| typed == expected)
+            .count()
+    }
+
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Whether the player ever kept typing after a mistake instead of deleting it first.
+    pub fn typed_past_error(&self) -> bool {
+        self.typed_past_error
+    }
+
+    pub fn is_complete(&self, text: &[char]) -> bool {
+        !text.is_empty() && self.correct_chars(text) == text.len()
+    }
+
+    /// Percentage of the text typed correctly.
+    pub fn progress(&self, text: &[char]) -> u32 {
+        if text.is_empty() {
+            return 0;
+        }
+        (self.correct_chars(text) * 100 / text.len()) as u32
+    }
+
+    /// Words per minute using the standard five-character word.
+    pub fn wpm(&self, text: &[char], elapsed_ms: i64) -> u32 {
+        if elapsed_ms <= 0 {
+            return 0;
+        }
+        let words = self.correct_chars(text) as f64 / 5.0;
+        let minutes = elapsed_ms as f64 / 60_000.0;
+        (words / minutes).round() as u32
+    }
+
+    /// Percentage of keystrokes that matched the text.
+    pub fn accuracy(&self) -> f32 {
+        if self.keystrokes == 0 {
+            return 0.0;
+        }
+        let correct = self.keystrokes - self.errors;
+        (correct as f32 / self.keystrokes as f32) * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "the quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn pasted_text_is_too_fast() {
+        let mut session = TypingSession::default();
+        assert!(session.too_fast(&TEXT.repeat(2), 0, 300));
+        assert!(!session.too_fast("the quick ", 0, 300));
+    }
+
+    #[test]
+    fn steady_typing_is_not_too_fast() {
+        let mut session = TypingSession::default();
+        // Five characters every 250 ms is 240 WPM
+        for tick in 0..400 {
+            assert!(!session.too_fast("brown", tick * 250, 300));
+        }
+    }
+
+    #[test]
+    fn idle_time_is_not_saved_up_for_a_paste() {
+        let mut session = TypingSession::default();
+        assert!(!session.too_fast("the quick ", 0, 300));
+        // A minute without typing, then 1400 characters at once
+        let paste: String = TEXT.chars().cycle().take(1_400).collect();
+        assert!(session.too_fast(&paste, 60_000, 300));
+        assert!(!session.too_fast("brown fox ", 60_000, 300));
+    }
+}
@@ -15,6 +15,8 @@ NC='\033[0m' # No Color
 # Configuration
 GAME_SERVER_URL="${GAME_SERVER_URL:-ws://127.0.0.1:8080}"
 TEST_TOKEN="${TEST_TOKEN:-}"
+# Signed game ticket for the game below, issued by Laravel
+TEST_TICKET="${TEST_TICKET:-GAME_TICKET_HERE}"
 
 echo "📍 Server URL: $GAME_SERVER_URL"
 echo ""
@@ -103,7 +105,10 @@ if [ -f "../artisan" ]; then
         echo "  websocat $GAME_SERVER_URL"
         echo ""
         echo "Then send:"
-        echo '  {"type":"Auth","token":"'$TEST_TOKEN'","game_id":"test-game-123","game_code":"TEST01","difficulty":"medium","text":"Test content","host_id":"test-host"}'
+        echo '  {"type":"auth","token":"'$TEST_TOKEN'","ticket":"'$TEST_TICKET'","game_id":"test-game-123","game_code":"TEST01","difficulty":"medium","host_id":"test-host"}'
+        echo ""
+        echo "Once the race has started, type with:"
+        echo '  {"type":"keystrokes","keys":"The quick "}'
     else
         echo -e "${YELLOW}⚠${NC} Could not generate token (check Laravel setup)"
     fi